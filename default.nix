@@ -33,6 +33,8 @@ buildRustPackage rec {
   postPatch = ''
   substituteInPlace share/applications/otptray.desktop \
     --replace /usr $out
+  substituteInPlace share/dbus-1/services/org.otptray.service \
+    --replace /usr $out
   '';
 
   installPhase = ''
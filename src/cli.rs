@@ -0,0 +1,644 @@
+//! Subcommands that work on the config without starting the tray, so
+//! otptray can be used over SSH and from scripts.
+
+use atomic_immut::AtomicImmut;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::mem::ManuallyDrop;
+use std::path::Path;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::common::*;
+use crate::instance::InstanceLock;
+
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("code")
+            .about("Print the current code for an entry")
+            .arg(entry_name_arg())
+            .arg(
+                Arg::with_name("remaining")
+                    .help("Also print the seconds until the code changes")
+                    .short("r")
+                    .long("remaining"),
+            ),
+        SubCommand::with_name("copy")
+            .about("Copy an entry's code from the running tray")
+            .arg(entry_name_arg()),
+        SubCommand::with_name("list")
+            .about("List entries, without their secrets")
+            .arg(
+                Arg::with_name("json")
+                    .help("Print the entries as JSON")
+                    .long("json"),
+            ),
+        SubCommand::with_name("add")
+            .about("Add an entry. The secret is read from stdin, or prompted for")
+            .arg(
+                Arg::with_name("name")
+                    .help("Name of the new entry")
+                    .required(true),
+            )
+            .args(&entry_field_args(Some("sha1"), Some("30"), Some("6"))),
+        SubCommand::with_name("edit")
+            .about("Change an entry's fields. Fields that aren't given are kept")
+            .arg(entry_name_arg())
+            .args(&entry_field_args(None, None, None))
+            .arg(
+                Arg::with_name("secret")
+                    .help("Replace the secret, read from stdin or prompted for")
+                    .long("secret"),
+            ),
+        SubCommand::with_name("remove")
+            .about("Move an entry to the trash")
+            .arg(entry_name_arg()),
+        SubCommand::with_name("rename")
+            .about("Rename an entry")
+            .arg(entry_name_arg())
+            .arg(
+                Arg::with_name("new_name")
+                    .help("New name for the entry")
+                    .required(true),
+            ),
+        SubCommand::with_name("pick")
+            .about("Pick an entry with dmenu, rofi, wofi or fuzzel, and copy its code")
+            .arg(
+                Arg::with_name("launcher")
+                    .help("Command that reads entry names on stdin and prints the one picked")
+                    .long("launcher")
+                    .env("OTPTRAY_LAUNCHER")
+                    .takes_value(true)
+                    .default_value(crate::pick::DEFAULT_LAUNCHER),
+            )
+            .arg(
+                Arg::with_name("type")
+                    .help("Type the code into the focused window instead of copying it")
+                    .long("type"),
+            )
+            .arg(
+                Arg::with_name("rofi_script")
+                    .help("Act as a rofi script mode, instead of running a launcher")
+                    .long("rofi-script")
+                    // rofi still holds the keyboard while the script
+                    // runs, so typing would go to rofi.
+                    .conflicts_with("type"),
+            )
+            .arg(
+                Arg::with_name("selection")
+                    .help("The row rofi passes back in script mode")
+                    .requires("rofi_script"),
+            ),
+        SubCommand::with_name("tui").about("Show all codes in a full-screen terminal dashboard"),
+        SubCommand::with_name("serve")
+            .about("Answer requests on the control socket without showing a tray"),
+        SubCommand::with_name("export")
+            .about("Export entries, secrets included, to move them to another app")
+            .arg(
+                Arg::with_name("names")
+                    .help("Entries to export, matched like for `code`. Defaults to all of them")
+                    .multiple(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .help("Output format")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["uri", "json", "csv"])
+                    .default_value("uri"),
+            )
+            .arg(
+                Arg::with_name("qr")
+                    .help("Show a single entry as a QR code for a phone to scan")
+                    .long("qr")
+                    .conflicts_with("format"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .help("File to write to, readable only by you. Defaults to stdout")
+                    .short("o")
+                    .long("output")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("include_secrets")
+                    .help("Acknowledge that the export contains your secrets")
+                    .long("include-secrets")
+                    .required(true),
+            ),
+    ]
+}
+
+fn entry_name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("name")
+        .help("Entry name, a unique prefix of it, or its letters in order")
+        .required(true)
+}
+
+/// The optional fields shared by `add` and `edit`. Secrets are never
+/// taken as arguments, since those end up in shell history and the
+/// process list.
+fn entry_field_args(
+    default_hash_fn: Option<&'static str>,
+    default_step: Option<&'static str>,
+    default_digit_count: Option<&'static str>,
+) -> Vec<Arg<'static, 'static>> {
+    let with_default = |arg: Arg<'static, 'static>, default: Option<&'static str>| match default {
+        Some(default) => arg.default_value(default),
+        None => arg,
+    };
+    vec![
+        Arg::with_name("issuer").long("issuer").takes_value(true),
+        Arg::with_name("account").long("account").takes_value(true),
+        Arg::with_name("tags")
+            .help("Comma separated tags")
+            .long("tags")
+            .takes_value(true),
+        Arg::with_name("notes").long("notes").takes_value(true),
+        with_default(
+            Arg::with_name("hash_fn")
+                .long("hash-fn")
+                .takes_value(true)
+                .possible_values(&["sha1", "sha256", "sha512"]),
+            default_hash_fn,
+        ),
+        with_default(
+            Arg::with_name("step").long("step").takes_value(true),
+            default_step,
+        ),
+        with_default(
+            Arg::with_name("digit_count")
+                .long("digits")
+                .takes_value(true),
+            default_digit_count,
+        ),
+    ]
+}
+
+pub fn run(
+    subcommand: &str,
+    matches: &ArgMatches,
+    config_location: &ConfigLocation,
+) -> Result<(), Error> {
+    // The tray owns the clipboard, so copying is left to it.
+    if subcommand == "copy" {
+        let name = matches.value_of("name").unwrap_or_default().to_string();
+        crate::instance::forward_request(
+            config_location,
+            &ControlRequest::Copy(EntrySelector::Name { name }),
+        )?;
+        return Ok(());
+    }
+    // Commands that only read codes leave the config file as it is,
+    // even if it's due for an upgrade.
+    let app_state = match subcommand {
+        "add" | "edit" | "remove" | "rename" | "serve" => {
+            AppState::load_from_config(config_location)?
+        }
+        _ => AppState::load_read_only(config_location)?,
+    };
+    match subcommand {
+        "code" => code(&app_state, matches),
+        "list" => list(&app_state, matches),
+        "add" => add(&app_state, matches),
+        "edit" => edit(&app_state, matches),
+        "remove" => remove(&app_state, matches),
+        "rename" => rename(&app_state, matches),
+        "export" => export(&app_state, matches),
+        "tui" => crate::tui::run(app_state),
+        "serve" => serve(app_state),
+        "pick" if matches.is_present("rofi_script") => {
+            crate::pick::rofi_script(&app_state, matches.value_of("selection"))
+        }
+        "pick" => crate::pick::pick(
+            &app_state,
+            matches.value_of("launcher").unwrap_or_default(),
+            matches.is_present("type"),
+        ),
+        other => unreachable!("Unknown subcommand: {}", other),
+    }
+}
+
+fn code(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let entry = app_state.find_entry_by_name(matches.value_of("name").unwrap_or_default())?;
+    let otp_value = entry.get_otp_value();
+    if matches.is_present("remaining") {
+        println!("{} {}", otp_value.otp, entry.seconds_remaining());
+    } else {
+        println!("{}", otp_value.otp);
+    }
+    Ok(())
+}
+
+/// Answer the control socket for other tools, for sessions without a
+/// tray. Copying and the setup window need the tray, so those requests
+/// are refused. Requests are handled like the tray handles them, so
+/// once locked, only another lock is accepted until otptray restarts.
+fn serve(app_state: AppState) -> Result<(), Error> {
+    let instance_lock = InstanceLock::acquire()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "otptray is already running"))?;
+    crate::instance::record_config(&app_state.config_location)?;
+    let _ = SimpleLogger::new().init();
+    let app_state = AtomicImmut::new(app_state);
+    instance_lock.serve(move |request| {
+        let current = app_state.load();
+        let (response, event) = current.handle_control_request(request)?;
+        match event {
+            None => {}
+            Some(UiEvent::Reload) => {
+                app_state.store(AppState::load_from_config(&current.config_location)?)
+            }
+            Some(UiEvent::Lock) => app_state.store(current.lock()),
+            Some(_) => {
+                return Err(
+                    io::Error::new(io::ErrorKind::Other, "that needs the tray running").into(),
+                )
+            }
+        }
+        Ok(response)
+    })
+}
+
+/// What `list --json` prints for each entry. Secrets are left out.
+#[derive(Serialize)]
+struct EntrySummary<'a> {
+    id: Uuid,
+    name: &'a str,
+    issuer: Option<&'a str>,
+    account: Option<&'a str>,
+    group: Option<&'a str>,
+    tags: &'a [String],
+    notes: Option<&'a str>,
+    hash_fn: &'a str,
+    step: u64,
+    digit_count: u32,
+    pinned: bool,
+    managed_by: Option<&'a Path>,
+}
+
+impl<'a> From<&'a OtpEntry> for EntrySummary<'a> {
+    fn from(entry: &'a OtpEntry) -> Self {
+        EntrySummary {
+            id: entry.id,
+            name: &entry.name,
+            issuer: entry.issuer.as_deref(),
+            account: entry.account.as_deref(),
+            group: entry.group.as_deref(),
+            tags: &entry.tags,
+            notes: entry.notes.as_deref(),
+            hash_fn: &entry.hash_fn,
+            step: entry.step,
+            digit_count: entry.digit_count,
+            pinned: entry.pinned,
+            managed_by: match &entry.source {
+                EntrySource::User => None,
+                EntrySource::Managed(path) => Some(path),
+            },
+        }
+    }
+}
+
+fn list(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    if matches.is_present("json") {
+        let summaries = app_state
+            .otp_entries
+            .iter()
+            .map(EntrySummary::from)
+            .collect::<Vec<_>>();
+        let json = serde_json::to_string_pretty(&summaries).map_err(io::Error::from)?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    let header = ["NAME", "ISSUER", "ACCOUNT", "GROUP", "TAGS"];
+    let rows = app_state
+        .otp_entries
+        .iter()
+        .map(|entry| {
+            [
+                entry.name.clone(),
+                entry.issuer.clone().unwrap_or_default(),
+                entry.account.clone().unwrap_or_default(),
+                entry.group.clone().unwrap_or_default(),
+                entry.tags.join(","),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = header
+        .iter()
+        .map(|title| title.chars().count())
+        .collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", format_row(header.to_vec()));
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+    Ok(())
+}
+
+fn add(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let secret = read_secret()?;
+    let entry = OtpEntry::input_validate(
+        matches.value_of("name").unwrap_or_default().to_string(),
+        matches.value_of("step").unwrap_or_default().to_string(),
+        &secret,
+        matches.value_of("hash_fn").unwrap_or_default().to_string(),
+        matches
+            .value_of("digit_count")
+            .unwrap_or_default()
+            .to_string(),
+    )?
+    .with_details(
+        matches.value_of("issuer").unwrap_or_default().to_string(),
+        matches.value_of("account").unwrap_or_default().to_string(),
+        matches.value_of("tags").unwrap_or_default().to_string(),
+        matches.value_of("notes").unwrap_or_default().to_string(),
+    );
+    let name = entry.name.clone();
+    app_state
+        .save_entry(entry, EntryAction::Add)?
+        .save_to_config()?;
+    eprintln!("Added {}", name);
+    Ok(())
+}
+
+fn edit(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let entry = app_state.find_entry_by_name(matches.value_of("name").unwrap_or_default())?;
+    let new_secret = if matches.is_present("secret") {
+        Some(read_secret()?)
+    } else {
+        None
+    };
+    let secret = match new_secret {
+        Some(ref new_secret) => new_secret.as_str(),
+        None => entry.secret_hash.expose(),
+    };
+    let field =
+        |name: &str, current: String| matches.value_of(name).map(String::from).unwrap_or(current);
+    let edited = OtpEntry::input_validate(
+        entry.name.clone(),
+        field("step", entry.step.to_string()),
+        secret,
+        field("hash_fn", entry.hash_fn.clone()),
+        field("digit_count", entry.digit_count.to_string()),
+    )?
+    .with_details(
+        field("issuer", entry.issuer.clone().unwrap_or_default()),
+        field("account", entry.account.clone().unwrap_or_default()),
+        field("tags", entry.tags.join(", ")),
+        field("notes", entry.notes.clone().unwrap_or_default()),
+    );
+    app_state
+        .save_entry(edited, EntryAction::Edit(entry.id))?
+        .save_to_config()?;
+    eprintln!("Updated {}", entry.name);
+    Ok(())
+}
+
+fn remove(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let entry = app_state.find_entry_by_name(matches.value_of("name").unwrap_or_default())?;
+    app_state.remove_entry(entry.id)?.save_to_config()?;
+    eprintln!("Moved {} to the trash", entry.name);
+    Ok(())
+}
+
+fn rename(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let entry = app_state.find_entry_by_name(matches.value_of("name").unwrap_or_default())?;
+    let renamed = OtpEntry::input_validate(
+        matches.value_of("new_name").unwrap_or_default().to_string(),
+        entry.step.to_string(),
+        entry.secret_hash.expose(),
+        entry.hash_fn.clone(),
+        entry.digit_count.to_string(),
+    )?;
+    let renamed = OtpEntry {
+        issuer: entry.issuer.clone(),
+        account: entry.account.clone(),
+        tags: entry.tags.clone(),
+        notes: entry.notes.clone(),
+        ..renamed
+    };
+    let new_name = renamed.name.clone();
+    app_state
+        .save_entry(renamed, EntryAction::Edit(entry.id))?
+        .save_to_config()?;
+    eprintln!("Renamed {} to {}", entry.name, new_name);
+    Ok(())
+}
+
+/// What `export --format json` writes for each entry.
+#[derive(Serialize)]
+struct ExportedEntry<'a> {
+    name: &'a str,
+    issuer: Option<&'a str>,
+    account: Option<&'a str>,
+    group: Option<&'a str>,
+    tags: &'a [String],
+    notes: Option<&'a str>,
+    secret: &'a str,
+    hash_fn: &'a str,
+    step: u64,
+    digit_count: u32,
+}
+
+impl<'a> From<&'a OtpEntry> for ExportedEntry<'a> {
+    fn from(entry: &'a OtpEntry) -> Self {
+        ExportedEntry {
+            name: &entry.name,
+            issuer: entry.issuer.as_deref(),
+            account: entry.account.as_deref(),
+            group: entry.group.as_deref(),
+            tags: &entry.tags,
+            notes: entry.notes.as_deref(),
+            secret: entry.secret_hash.expose(),
+            hash_fn: &entry.hash_fn,
+            step: entry.step,
+            digit_count: entry.digit_count,
+        }
+    }
+}
+
+fn export(app_state: &AppState, matches: &ArgMatches) -> Result<(), Error> {
+    let entries = match matches.values_of("names") {
+        Some(names) => names
+            .map(|name| app_state.find_entry_by_name(name))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => app_state.otp_entries.iter().collect(),
+    };
+
+    let contents = if matches.is_present("qr") {
+        let entry = match entries.as_slice() {
+            [entry] => entry,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--qr shows one entry at a time, pick it by name",
+                )
+                .into())
+            }
+        };
+        let qr_code = qrcode::QrCode::new(entry.otpauth_uri().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        // Inverted, since terminals are usually light text on a dark
+        // background and scanners expect dark modules on light.
+        Zeroizing::new(
+            qr_code
+                .render::<qrcode::render::unicode::Dense1x2>()
+                .dark_color(qrcode::render::unicode::Dense1x2::Light)
+                .light_color(qrcode::render::unicode::Dense1x2::Dark)
+                .build()
+                + "\n",
+        )
+    } else {
+        match matches.value_of("format") {
+            Some("json") => {
+                let exported = entries
+                    .iter()
+                    .map(|entry| ExportedEntry::from(*entry))
+                    .collect::<Vec<_>>();
+                Zeroizing::new(
+                    serde_json::to_string_pretty(&exported).map_err(io::Error::from)? + "\n",
+                )
+            }
+            Some("csv") => {
+                let mut csv = Zeroizing::new(String::from(
+                    "name,issuer,account,group,tags,notes,secret,hash_fn,step,digit_count\n",
+                ));
+                for entry in &entries {
+                    let fields = [
+                        entry.name.as_str(),
+                        entry.issuer.as_deref().unwrap_or_default(),
+                        entry.account.as_deref().unwrap_or_default(),
+                        entry.group.as_deref().unwrap_or_default(),
+                        &entry.tags.join(","),
+                        entry.notes.as_deref().unwrap_or_default(),
+                        entry.secret_hash.expose(),
+                        &entry.hash_fn,
+                        &entry.step.to_string(),
+                        &entry.digit_count.to_string(),
+                    ];
+                    let row = fields
+                        .iter()
+                        .map(|field| csv_field(field))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    csv.push_str(&row);
+                    csv.push('\n');
+                }
+                csv
+            }
+            _ => {
+                let mut uris = Zeroizing::new(String::new());
+                for entry in &entries {
+                    uris.push_str(&entry.otpauth_uri());
+                    uris.push('\n');
+                }
+                uris
+            }
+        }
+    };
+
+    match matches.value_of("output") {
+        Some(path) => write_private_file(Path::new(path), contents.as_bytes()),
+        None => {
+            check_stdout_private()?;
+            io::stdout().write_all(contents.as_bytes())?;
+            Ok(())
+        }
+    }
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Refuse to write secrets to stdout when the shell redirected it to
+/// a file that other users can read. Terminals and pipes are fine.
+fn check_stdout_private() -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::FromRawFd;
+
+    // Borrow stdout's descriptor without closing it afterwards.
+    let stdout = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDOUT_FILENO) });
+    let metadata = stdout.metadata()?;
+    if metadata.is_file() && metadata.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "stdout is a file other users can read, use --output instead",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Read a secret from the first line of stdin. When stdin is a
+/// terminal, prompt for it without echoing what's typed.
+fn read_secret() -> Result<Zeroizing<String>, Error> {
+    let mut secret = Zeroizing::new(String::new());
+    if is_terminal(libc::STDIN_FILENO) {
+        eprint!("Secret: ");
+        io::stderr().flush()?;
+        let _hidden_input = HiddenInput::new();
+        io::stdin().lock().read_line(&mut secret)?;
+        eprintln!();
+    } else {
+        io::stdin().lock().read_line(&mut secret)?;
+    }
+    Ok(Zeroizing::new(secret.trim().to_string()))
+}
+
+fn is_terminal(fd: libc::c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Turns off terminal echo on stdin until dropped.
+struct HiddenInput(Option<libc::termios>);
+
+impl HiddenInput {
+    fn new() -> Self {
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return HiddenInput(None);
+            }
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return HiddenInput(None);
+            }
+            HiddenInput(Some(original))
+        }
+    }
+}
+
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        if let Some(original) = self.0 {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+            }
+        }
+    }
+}
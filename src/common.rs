@@ -1,29 +1,110 @@
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use totp_lite::{totp_custom, Sha1, Sha256, Sha512};
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
 
-static VALID_HASH_FNS: &'static [&str] = &["sha1", "sha256", "sha512"];
+use crate::secret::Secret;
+
+static VALID_HASH_FNS: &[&str] = &["sha1", "sha256", "sha512"];
+const BACKUP_COUNT: usize = 10;
+/// The config layout this build reads and writes. Bump it, and add a
+/// migration to `CONFIG_MIGRATIONS`, whenever the layout changes in a
+/// way older builds can't read. New fields with a `#[serde(default)]`
+/// don't need a bump.
+pub const CONFIG_VERSION: u32 = 1;
+pub const DEFAULT_PROFILE: &str = "default";
+/// Environment variable pointing at the default profile's config file,
+/// for when `--config` isn't given.
+pub const CONFIG_ENV_VAR: &str = "OTPTRAY_CONFIG";
+/// Drop-in directory for entries provisioned for every user.
+const SYSTEM_DROP_IN_DIR: &str = "/etc/otptray.d";
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3f";
+/// How long removed entries are kept in the trash, unless the config
+/// says otherwise.
+pub const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+/// How many entries the tray's "Recently used" section shows.
+const RECENTLY_USED_COUNT: usize = 3;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct OtpEntry {
+    /// Identifies the entry for as long as it exists, no matter where
+    /// it moves in the list. Entries written without one get one on
+    /// load, see `assign_entry_ids`.
+    #[serde(default = "Uuid::nil")]
+    pub id: Uuid,
     pub name: String,
     pub step: u64,
-    pub secret_hash: String,
+    pub secret_hash: Secret,
     pub hash_fn: String,
     pub digit_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The tray submenu the entry is shown in. Ungrouped entries are
+    /// shown at the top level of the menu.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// How many times the entry's code has been copied.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub use_count: u64,
+    /// Pinned entries are shown at the top of the tray menu.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    #[serde(skip)]
+    pub source: EntrySource,
+}
+
+/// Where an entry was loaded from. Managed entries come from drop-in
+/// files, and are read-only in otptray.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EntrySource {
+    #[default]
+    User,
+    Managed(PathBuf),
+}
+
+impl EntrySource {
+    pub fn formatted_display(&self) -> String {
+        match self {
+            EntrySource::User => "Your config".to_string(),
+            EntrySource::Managed(path) => format!("Managed by {}", path.display()),
+        }
+    }
 }
 
 impl OtpEntry {
+    pub fn is_managed(&self) -> bool {
+        self.source != EntrySource::User
+    }
+
+    /// The secret is borrowed, so the only copy made of it is the one
+    /// that ends up in the entry.
     pub fn input_validate(
         name: String,
         step: String,
-        secret_hash: String,
+        secret_hash: &str,
         hash_fn: String,
         digit_count: String,
     ) -> Result<Self, ValidationError> {
@@ -56,23 +137,288 @@ impl OtpEntry {
         Ok(OtpEntry {
             name,
             step: step_parsed,
-            secret_hash,
+            secret_hash: Secret::new(secret_hash.to_string()),
             hash_fn,
             digit_count: digit_count_parsed as u32,
+            ..Default::default()
         })
     }
+
+    /// Fill in the optional descriptive fields from free-form input.
+    /// Blank fields are left unset, and tags are comma separated.
+    pub fn with_details(
+        self,
+        issuer: String,
+        account: String,
+        tags: String,
+        notes: String,
+    ) -> Self {
+        fn non_empty(value: String) -> Option<String> {
+            let trimmed = value.trim();
+            if trimmed.is_empty() {
+                None
+            } else {
+                Some(trimmed.to_string())
+            }
+        }
+        OtpEntry {
+            issuer: non_empty(issuer),
+            account: non_empty(account),
+            tags: tags
+                .split(',')
+                .filter_map(|tag| non_empty(tag.to_string()))
+                .collect(),
+            notes: non_empty(notes),
+            ..self
+        }
+    }
+
+    /// Whether every word of `query` shows up in the entry's name,
+    /// issuer, tags or notes, ignoring case.
+    pub fn matches_search(&self, query: &str) -> bool {
+        SearchableEntry::from(self).matches(query)
+    }
+
+    /// Whether the letters of `query` appear in the entry's name in
+    /// order, ignoring case, so "ghb" matches "GitHub".
+    fn fuzzy_matches_name(&self, query: &str) -> bool {
+        let name = self.name.to_lowercase();
+        let mut name_chars = name.chars();
+        query
+            .to_lowercase()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .all(|c| name_chars.any(|name_char| name_char == c))
+    }
+
+    /// Issuer, account and tags on one line, for showing under the
+    /// entry's name.
+    pub fn formatted_details(&self) -> String {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| format!("#{}", tag))
+            .collect::<Vec<_>>()
+            .join(" ");
+        vec![
+            self.issuer.clone().unwrap_or_default(),
+            self.account.clone().unwrap_or_default(),
+            tags,
+        ]
+        .into_iter()
+        .filter(|detail| !detail.is_empty())
+        .collect::<Vec<_>>()
+        .join(" · ")
+    }
+
+    /// Render a tray label from a format like `{issuer} ({account}): {code}`.
+    /// Supports `{name}`, `{issuer}`, `{account}`, `{tags}` and `{code}`,
+    /// with unset fields rendered as empty.
+    pub fn formatted_label(&self, label_format: &str, otp: &str) -> String {
+        label_format
+            .replace("{name}", &self.name)
+            .replace("{issuer}", self.issuer.as_deref().unwrap_or_default())
+            .replace("{account}", self.account.as_deref().unwrap_or_default())
+            .replace("{tags}", &self.tags.join(", "))
+            .replace("{code}", otp)
+    }
+}
+
+/// What an entry is searched on, without the rest of the entry, for
+/// search boxes that keep their own copy of what they filter.
+#[derive(Clone, Debug)]
+pub struct SearchableEntry {
+    pub id: Uuid,
+    /// The name, issuer, notes and tags, lowercased.
+    fields: Vec<String>,
+}
+
+impl SearchableEntry {
+    pub fn matches(&self, query: &str) -> bool {
+        query
+            .to_lowercase()
+            .split_whitespace()
+            .all(|term| self.fields.iter().any(|field| field.contains(term)))
+    }
+}
+
+impl From<&OtpEntry> for SearchableEntry {
+    fn from(entry: &OtpEntry) -> Self {
+        SearchableEntry {
+            id: entry.id,
+            fields: [
+                Some(&entry.name),
+                entry.issuer.as_ref(),
+                entry.notes.as_ref(),
+            ]
+            .iter()
+            .flatten()
+            .copied()
+            .chain(entry.tags.iter())
+            .map(|field| field.to_lowercase())
+            .collect(),
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct AppState {
     pub otp_entries: Vec<OtpEntry>,
-    pub otp_codes: HashMap<u64, String>,
+    pub otp_codes: HashMap<u64, MenuCode>,
+    /// Group names, in the order their submenus are shown.
+    pub groups: Vec<String>,
+    pub idle_lock_seconds: Option<u64>,
+    pub menu_label_format: Option<String>,
+    pub menu_order: MenuOrder,
+    /// Removed entries, oldest first.
+    pub trash: Vec<TrashedEntry>,
+    pub trash_retention_days: Option<u64>,
+    pub locked: bool,
+    pub config_digest: Option<u64>,
+    pub config_version: u32,
+    pub config_location: ConfigLocation,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub enum EntryAction {
     Add,
-    Edit(usize),
+    Edit(Uuid),
+}
+
+/// How entries are sorted in the tray menu, both at the top level and
+/// inside each group.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MenuOrder {
+    /// The order the entries are listed in the config.
+    #[default]
+    Manual,
+    Alphabetical,
+    MostUsed,
+    MostRecent,
+}
+
+impl MenuOrder {
+    pub const ALL: [MenuOrder; 4] = [
+        MenuOrder::Manual,
+        MenuOrder::Alphabetical,
+        MenuOrder::MostUsed,
+        MenuOrder::MostRecent,
+    ];
+
+    pub fn is_manual(&self) -> bool {
+        *self == MenuOrder::Manual
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            MenuOrder::Manual => "manual",
+            MenuOrder::Alphabetical => "alphabetical",
+            MenuOrder::MostUsed => "most_used",
+            MenuOrder::MostRecent => "most_recent",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<MenuOrder> {
+        MenuOrder::ALL
+            .iter()
+            .copied()
+            .find(|order| order.id() == id)
+    }
+
+    pub fn formatted_display(&self) -> &'static str {
+        match self {
+            MenuOrder::Manual => "Manual",
+            MenuOrder::Alphabetical => "Alphabetical",
+            MenuOrder::MostUsed => "Most used",
+            MenuOrder::MostRecent => "Most recent",
+        }
+    }
+}
+
+/// Changes made to several entries at once from the setup window.
+/// Fields left as `None` are kept as they are on each entry.
+#[derive(Clone, Debug, Default)]
+pub struct BulkEdit {
+    pub add_tags: Vec<String>,
+    pub hash_fn: Option<String>,
+    pub step: Option<u64>,
+    pub digit_count: Option<u32>,
+}
+
+impl BulkEdit {
+    /// Blank fields mean "unchanged", and tags are comma separated.
+    pub fn input_validate(
+        add_tags: String,
+        hash_fn: Option<String>,
+        step: String,
+        digit_count: String,
+    ) -> Result<Self, ValidationError> {
+        if let Some(ref hash_fn) = hash_fn {
+            if !VALID_HASH_FNS.contains(&hash_fn.as_str()) {
+                return Err(ValidationError::InvalidSelection {
+                    field: "hash function",
+                    candidate: hash_fn.clone(),
+                    valid_selections: VALID_HASH_FNS,
+                });
+            }
+        }
+        let step = match step.trim() {
+            "" => None,
+            step => Some(step.parse::<u64>()?),
+        };
+        let digit_count = match digit_count.trim() {
+            "" => None,
+            digit_count => Some(digit_count.parse::<u8>()? as u32),
+        };
+        Ok(BulkEdit {
+            add_tags: add_tags
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+            hash_fn,
+            step,
+            digit_count,
+        })
+    }
+
+    fn apply(&self, entry: &mut OtpEntry) {
+        for tag in &self.add_tags {
+            if !entry.tags.contains(tag) {
+                entry.tags.push(tag.clone());
+            }
+        }
+        if let Some(ref hash_fn) = self.hash_fn {
+            entry.hash_fn = hash_fn.clone();
+        }
+        if let Some(step) = self.step {
+            entry.step = step;
+        }
+        if let Some(digit_count) = self.digit_count {
+            entry.digit_count = digit_count;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum GroupAction {
+    Create,
+    Rename,
+    Delete,
+    MoveEntries(Vec<Uuid>),
+}
+
+impl GroupAction {
+    pub fn window_title(&self) -> &'static str {
+        match self {
+            GroupAction::Create => "New Group",
+            GroupAction::Rename => "Rename Group",
+            GroupAction::Delete => "Delete Group",
+            GroupAction::MoveEntries(_) => "Move to Group",
+        }
+    }
 }
 
 impl EntryAction {
@@ -87,24 +433,68 @@ impl EntryAction {
 impl Default for OtpEntry {
     fn default() -> Self {
         Self {
+            id: Uuid::new_v4(),
             name: "".to_string(),
-            secret_hash: "".to_string(),
+            secret_hash: Secret::default(),
             hash_fn: "sha1".to_string(), // Google Authenticator defaults
             step: 30,                    // Google Authenticator defaults
             digit_count: 6,              // Google Authenticator defaults
+            issuer: None,
+            account: None,
+            tags: Vec::new(),
+            group: None,
+            notes: None,
+            created_at: None,
+            last_used_at: None,
+            use_count: 0,
+            pinned: false,
+            source: EntrySource::User,
         }
     }
 }
 
 impl OtpEntry {
+    /// The entry as an `otpauth://` URI, the format authenticator
+    /// apps import from QR codes. It includes the secret.
+    pub fn otpauth_uri(&self) -> Zeroizing<String> {
+        let label = match (&self.issuer, &self.account) {
+            (Some(issuer), Some(account)) => format!("{}:{}", issuer, account),
+            (Some(issuer), None) => format!("{}:{}", issuer, self.name),
+            (None, Some(account)) => account.clone(),
+            (None, None) => self.name.clone(),
+        };
+        let mut uri = Zeroizing::new(format!(
+            "otpauth://totp/{}?secret={}&algorithm={}&digits={}&period={}",
+            percent_encode(&label),
+            percent_encode(self.secret_hash.expose()),
+            self.hash_fn.to_uppercase(),
+            self.digit_count,
+            self.step
+        ));
+        if let Some(ref issuer) = self.issuer {
+            uri.push_str("&issuer=");
+            uri.push_str(&percent_encode(issuer));
+        }
+        uri
+    }
+
+    /// Seconds until the current code changes.
+    pub fn seconds_remaining(&self) -> u64 {
+        let unix_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.step - unix_epoch % self.step.max(1)
+    }
+
     pub fn get_otp_value(&self) -> OtpValue {
         let unix_epoch = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let secret = base32::decode(
+        let mut secret = base32::decode(
             base32::Alphabet::RFC4648 { padding: false },
-            &self.secret_hash,
+            self.secret_hash.expose(),
         )
         .unwrap_or_default(); // TODO: Proper error handling.
         let otp = match &self.hash_fn[..] {
@@ -113,6 +503,7 @@ impl OtpEntry {
             "sha512" => totp_custom::<Sha512>(self.step, self.digit_count, &secret, unix_epoch),
             other => panic!("Unknown hash function: {}", other),
         };
+        secret.zeroize();
         OtpValue {
             name: self.name.clone(),
             otp,
@@ -125,67 +516,360 @@ impl Default for AppState {
         Self {
             otp_entries: Vec::new(),
             otp_codes: HashMap::new(),
+            groups: Vec::new(),
+            idle_lock_seconds: None,
+            menu_label_format: None,
+            menu_order: MenuOrder::default(),
+            trash: Vec::new(),
+            trash_retention_days: None,
+            locked: false,
+            config_digest: None,
+            config_version: CONFIG_VERSION,
+            config_location: ConfigLocation::default(),
         }
     }
 }
 
 impl AppState {
-    pub fn config_path() -> Result<PathBuf, Error> {
-        let config_dir = dirs::config_dir().ok_or(Error::NoUserConfigDir)?;
-        Ok(config_dir.join("otptray.yaml"))
+    /// Load the config at `config_location`. Configs written by an
+    /// older otptray are upgraded on disk right away, and the original
+    /// is kept as a backup like with any other save. Configs written by
+    /// a newer otptray are loaded, but never written.
+    ///
+    /// Managed entries from the drop-in directories are added after
+    /// the user's own entries.
+    pub fn load_from_config(config_location: &ConfigLocation) -> Result<AppState, Error> {
+        config_location.migrate_legacy_layout()?;
+        let mut app_state = AppState {
+            config_location: config_location.clone(),
+            ..Self::load_from_path(&config_location.config_path()?)?
+        };
+        let ids_assigned = app_state.assign_own_entry_ids();
+        if app_state.config_version < CONFIG_VERSION {
+            log::info!(
+                "Upgrading configuration file from version {} to {}",
+                app_state.config_version,
+                CONFIG_VERSION
+            );
+            app_state = app_state.save_to_config()?;
+        } else if ids_assigned && app_state.config_version == CONFIG_VERSION {
+            // Saved right away, so the ids stay the same across reloads.
+            log::info!("Saving ids given to entries without one");
+            app_state = app_state.save_to_config()?;
+        }
+        app_state.with_managed_entries()
+    }
+
+    /// Like `load_from_config`, but never writes anything. A config in
+    /// the legacy location is read where it is, and older layouts and
+    /// missing ids are only upgraded in memory. Meant for commands that
+    /// only read codes. The state it returns isn't meant to be saved,
+    /// so commands that record usage do so with `record_use`.
+    pub fn load_read_only(config_location: &ConfigLocation) -> Result<AppState, Error> {
+        let config_path = match config_location.legacy_config_path()? {
+            Some(legacy_path) => legacy_path,
+            None => config_location.config_path()?,
+        };
+        let mut app_state = AppState {
+            config_location: config_location.clone(),
+            ..Self::load_from_path(&config_path)?
+        };
+        app_state.assign_own_entry_ids();
+        app_state.with_managed_entries()
+    }
+
+    /// Give the user's entries, including the trashed ones, ids where
+    /// they are missing. Returns whether any entry got one.
+    fn assign_own_entry_ids(&mut self) -> bool {
+        assign_entry_ids(
+            self.otp_entries
+                .iter_mut()
+                .chain(self.trash.iter_mut().map(|trashed| &mut trashed.entry)),
+        )
+    }
+
+    fn with_managed_entries(mut self) -> Result<AppState, Error> {
+        self.otp_entries
+            .extend(self.config_location.managed_entries()?);
+        assign_entry_ids(self.otp_entries.iter_mut());
+        Ok(self.empty_expired_trash(Utc::now()))
     }
 
-    pub fn load_from_config() -> Result<AppState, Error> {
-        match OpenOptions::new().read(true).open(Self::config_path()?) {
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
-            Err(err) => Err(err.into()),
-            Ok(file) => {
-                let config: OtpTrayConfig = serde_yaml::from_reader(&file)?;
+    fn load_from_path(path: &Path) -> Result<AppState, Error> {
+        match read_config_file(path)? {
+            None => Ok(Default::default()),
+            Some(contents) => {
+                let (config, config_version) = OtpTrayConfig::parse(&contents)?;
                 Ok(AppState {
                     otp_entries: config.entries,
+                    groups: config.groups,
+                    idle_lock_seconds: config.idle_lock_seconds,
+                    menu_label_format: config.menu_label_format,
+                    menu_order: config.menu_order,
+                    trash: config.trash,
+                    trash_retention_days: config.trash_retention_days,
+                    config_digest: Some(config_digest(&contents)),
+                    config_version,
                     ..Default::default()
                 })
             }
         }
     }
 
-    pub fn save_to_config(&self) -> Result<(), Error> {
-        #[cfg(target_family = "unix")]
-        use std::os::unix::fs::OpenOptionsExt;
+    /// Whether the config file still holds exactly what this state was
+    /// last loaded from or saved as.
+    pub fn config_unchanged(&self) -> Result<bool, Error> {
+        let on_disk = read_config_file(&self.config_location.config_path()?)?;
+        Ok(on_disk.map(|contents| config_digest(&contents)) == self.config_digest)
+    }
+
+    /// Write the config to a temporary file next to the real one, sync
+    /// it, then rename it over the old one, so a failed write never
+    /// leaves a truncated config behind. The previous config is kept
+    /// as a timestamped backup first.
+    ///
+    /// If the file was changed by someone else since this state was
+    /// loaded, nothing is written and `Error::Conflict` is returned.
+    /// On success, the returned state tracks the newly written file.
+    pub fn save_to_config(&self) -> Result<AppState, Error> {
+        self.write_config(true)
+    }
+
+    /// Like `save_to_config`, but without keeping a backup. Meant for
+    /// bookkeeping like usage timestamps, so that routine use doesn't
+    /// push meaningful backups out of rotation.
+    pub fn save_usage_to_config(&self) -> Result<AppState, Error> {
+        self.write_config(false)
+    }
+
+    fn write_config(&self, keep_backup: bool) -> Result<AppState, Error> {
+        if self.config_version > CONFIG_VERSION {
+            return Err(Error::UnsupportedVersion {
+                version: self.config_version,
+            });
+        }
+        let config_path = self.config_location.config_path()?;
+        let on_disk = read_config_file(&config_path)?;
+        if on_disk.as_ref().map(|contents| config_digest(contents)) != self.config_digest {
+            return Err(Error::Conflict);
+        }
 
-        let mut base_options = OpenOptions::new();
-        base_options
-            .write(true)
+        if let Some(config_dir) = config_path.parent() {
+            create_private_dir(config_dir)?;
+        }
+        let contents = self.serialize_config()?;
+        let temp_path = config_path.with_extension("yaml.tmp");
+        let write_result = private_file_options()
             .create(true)
             .truncate(true)
-            .read(true);
+            .open(&temp_path)
+            .map_err(Error::from)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()?;
+                Ok(())
+            });
+        if let Err(err) = write_result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
 
-        if cfg!(unix) {
-            base_options.mode(0o600);
+        match on_disk {
+            Some(previous) if keep_backup => self.config_location.write_backup(&previous)?,
+            _ => {}
         }
+        fs::rename(&temp_path, &config_path)?;
+        sync_parent_dir(&config_path)?;
+        Ok(AppState {
+            config_digest: Some(config_digest(contents.as_bytes())),
+            config_version: CONFIG_VERSION,
+            ..self.clone()
+        })
+    }
 
-        base_options
-            .open(Self::config_path()?)
-            .map_err(|err| err.into())
-            .and_then(|file| {
-                let config = OtpTrayConfig {
-                    entries: self.otp_entries.clone(),
-                };
-                serde_yaml::to_writer(&file, &config).map_err(|err| err.into())
-            })
+    /// Save the state, returning whatever should become the current
+    /// state. If the config file was edited behind our back, the file
+    /// on disk wins and this state is kept as a backup instead.
+    pub fn save_or_reload(self) -> AppState {
+        match self.save_to_config() {
+            Ok(saved_state) => saved_state,
+            Err(Error::Conflict) => {
+                log::warn!("Configuration file changed on disk, keeping our changes as a backup");
+                if let Err(err) = self.save_as_backup() {
+                    log::error!("Failed to back up conflicting changes: {:?}", err);
+                }
+                AppState::load_from_config(&self.config_location).unwrap_or_else(|err| {
+                    log::error!("Failed to reload configuration file: {:?}", err);
+                    self
+                })
+            }
+            Err(err) => {
+                log::error!("Failed to save configuration file: {:?}", err);
+                self
+            }
+        }
+    }
+
+    /// Keep this state as a backup without touching the config file,
+    /// so changes that lost a save conflict can still be restored.
+    pub fn save_as_backup(&self) -> Result<(), Error> {
+        self.config_location
+            .write_backup(self.serialize_config()?.as_bytes())
+    }
+
+    fn serialize_config(&self) -> Result<Zeroizing<String>, Error> {
+        let config = OtpTrayConfig {
+            version: CONFIG_VERSION,
+            entries: self
+                .otp_entries
+                .iter()
+                .filter(|entry| !entry.is_managed())
+                .cloned()
+                .collect(),
+            groups: self.groups.clone(),
+            idle_lock_seconds: self.idle_lock_seconds,
+            menu_label_format: self.menu_label_format.clone(),
+            menu_order: self.menu_order,
+            trash: self.trash.clone(),
+            trash_retention_days: self.trash_retention_days,
+        };
+        Ok(Zeroizing::new(serde_yaml::to_string(&config)?))
+    }
+
+    /// Load a backup and make it the current config. The config being
+    /// replaced gets backed up like any other save.
+    pub fn restore_backup(&self, backup_path: &Path) -> Result<AppState, Error> {
+        let mut backup = Self::load_from_path(backup_path)?;
+        backup.otp_entries.extend(
+            self.otp_entries
+                .iter()
+                .filter(|entry| entry.is_managed())
+                .cloned(),
+        );
+        backup.assign_own_entry_ids();
+        AppState {
+            otp_entries: backup.otp_entries,
+            groups: backup.groups,
+            idle_lock_seconds: backup.idle_lock_seconds,
+            menu_label_format: backup.menu_label_format,
+            menu_order: backup.menu_order,
+            trash: backup.trash,
+            trash_retention_days: backup.trash_retention_days,
+            config_version: backup.config_version,
+            ..self.clone()
+        }
+        .save_to_config()
     }
 
-    pub fn add_otp_value<T: Hash>(&mut self, entry: &T, otp_code: String) -> u64 {
+    pub fn add_otp_value<T: Hash>(
+        &mut self,
+        menu_item: &T,
+        entry_id: Uuid,
+        otp_code: String,
+    ) -> u64 {
         let mut hasher = DefaultHasher::new();
-        entry.hash(&mut hasher);
+        menu_item.hash(&mut hasher);
         let key = hasher.finish();
-        self.otp_codes.insert(key, otp_code);
+        self.otp_codes.insert(
+            key,
+            MenuCode {
+                entry_id,
+                otp: otp_code,
+            },
+        );
         key
     }
 
     // TODO: Deprecate!
     pub fn get_otp_value_by_id(&self, id: u64) -> Option<&String> {
-        self.otp_codes.get(&id)
+        self.otp_codes.get(&id).map(|menu_code| &menu_code.otp)
+    }
+
+    pub fn get_entry_id_by_menu_id(&self, id: u64) -> Option<Uuid> {
+        self.otp_codes.get(&id).map(|menu_code| menu_code.entry_id)
+    }
+
+    pub fn get_entry(&self, id: Uuid) -> Option<&OtpEntry> {
+        self.otp_entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Look up an entry by name, for the command line. An exact match
+    /// wins, then a unique prefix, then a unique fuzzy match, all
+    /// ignoring case.
+    pub fn find_entry_by_name(&self, query: &str) -> Result<&OtpEntry, Error> {
+        let lowercase_query = query.to_lowercase();
+        let matchers: [&dyn Fn(&OtpEntry) -> bool; 3] = [
+            &|entry| entry.name.to_lowercase() == lowercase_query,
+            &|entry| entry.name.to_lowercase().starts_with(&lowercase_query),
+            &|entry| entry.fuzzy_matches_name(query),
+        ];
+        for matcher in &matchers {
+            let matches = self
+                .otp_entries
+                .iter()
+                .filter(|entry| matcher(entry))
+                .collect::<Vec<_>>();
+            match matches.as_slice() {
+                [] => continue,
+                [entry] => return Ok(entry),
+                _ => {
+                    return Err(Error::AmbiguousEntry {
+                        query: query.to_string(),
+                        candidates: matches.iter().map(|entry| entry.name.clone()).collect(),
+                    })
+                }
+            }
+        }
+        Err(Error::NoMatchingEntry(query.to_string()))
+    }
+
+    fn entry_position(&self, id: Uuid) -> Result<usize, Error> {
+        self.otp_entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or(Error::EntryNotFound(id))
+    }
+
+    /// The tray menu label for an entry, using `menu_label_format`
+    /// from the config if there is one.
+    pub fn formatted_menu_label(&self, entry: &OtpEntry, otp_value: &OtpValue) -> String {
+        match &self.menu_label_format {
+            Some(label_format) => entry.formatted_label(label_format, &otp_value.otp),
+            None => otp_value.formatted_menu_display(),
+        }
+    }
+
+    /// Save a use of the entry, for states loaded with `load_read_only`.
+    /// The config is loaded again like the tray loads it, so a legacy
+    /// or outdated config is moved or upgraded, with a backup, before
+    /// the usage is saved. Returns the state as saved.
+    pub fn record_use(&self, id: Uuid) -> Result<AppState, Error> {
+        let position = self.entry_position(id)?;
+        let current = AppState::load_from_config(&self.config_location)?;
+        // Ids missing from the config were only given in memory, and
+        // loading it again gives it others. The entry is found where
+        // it was instead.
+        let id = match current.get_entry(id) {
+            Some(_) => id,
+            None => match current.otp_entries.get(position) {
+                Some(entry) if entry.name == self.otp_entries[position].name => entry.id,
+                _ => return Err(Error::EntryNotFound(id)),
+            },
+        };
+        current.mark_used(id).save_usage_to_config()
+    }
+
+    /// Record that the entry's code was just used.
+    pub fn mark_used(&self, id: Uuid) -> AppState {
+        let mut otp_entries = self.otp_entries.clone();
+        if let Some(entry) = otp_entries.iter_mut().find(|entry| entry.id == id) {
+            entry.last_used_at = Some(Utc::now());
+            entry.use_count += 1;
+        }
+        Self {
+            otp_entries,
+            ..self.clone()
+        }
     }
 
     pub fn get_otp_value_at_index(&self, index: usize) -> Option<OtpValue> {
@@ -194,102 +878,1228 @@ impl AppState {
             .map(|entry| entry.get_otp_value())
     }
 
-    pub fn save_entry(&self, otp_entry: OtpEntry, entry_action: EntryAction) -> AppState {
+    pub fn save_entry(
+        &self,
+        otp_entry: OtpEntry,
+        entry_action: EntryAction,
+    ) -> Result<AppState, Error> {
         let mut entries = self.otp_entries.clone();
         let new_otp_entries = match entry_action {
             EntryAction::Add => {
-                entries.push(otp_entry);
+                let otp_entry = OtpEntry {
+                    id: Uuid::new_v4(),
+                    created_at: Some(Utc::now()),
+                    ..otp_entry
+                };
+                // New entries go after the user's own, but before any
+                // managed ones.
+                let position = entries
+                    .iter()
+                    .position(OtpEntry::is_managed)
+                    .unwrap_or(entries.len());
+                entries.insert(position, otp_entry);
                 entries
             }
-            EntryAction::Edit(index) => {
-                entries[index] = otp_entry;
+            EntryAction::Edit(id) => {
+                let index = self.entry_position(id)?;
+                if entries[index].is_managed() {
+                    return Err(Error::ManagedEntry);
+                }
+                entries[index] = OtpEntry {
+                    id,
+                    group: entries[index].group.clone(),
+                    created_at: entries[index].created_at,
+                    last_used_at: entries[index].last_used_at,
+                    use_count: entries[index].use_count,
+                    pinned: entries[index].pinned,
+                    ..otp_entry
+                };
                 entries
             }
         };
 
-        Self {
+        Ok(Self {
             otp_entries: new_otp_entries,
-            ..Default::default()
-        }
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Move an entry to the trash. It can be restored until it is
+    /// older than the trash retention period.
+    pub fn remove_entry(&self, id: Uuid) -> Result<AppState, Error> {
+        self.remove_entries(&[id])
     }
 
-    pub fn remove_entry_index(&self, index: usize) -> AppState {
+    /// Move several entries to the trash at once, so they are restored
+    /// together by `undo_remove`.
+    pub fn remove_entries(&self, ids: &[Uuid]) -> Result<AppState, Error> {
+        let deleted_at = Utc::now();
         let mut new_otp_entries = self.otp_entries.clone();
-        new_otp_entries.remove(index);
-        Self {
+        let mut trash = self.trash.clone();
+        for id in ids {
+            let index = new_otp_entries
+                .iter()
+                .position(|entry| entry.id == *id)
+                .ok_or(Error::EntryNotFound(*id))?;
+            if new_otp_entries[index].is_managed() {
+                return Err(Error::ManagedEntry);
+            }
+            trash.push(TrashedEntry {
+                entry: new_otp_entries.remove(index),
+                deleted_at,
+            });
+        }
+        Ok(Self {
             otp_entries: new_otp_entries,
-            ..Default::default()
+            trash,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Put an entry from the trash back at the end of the user's
+    /// entries.
+    pub fn restore_entry(&self, id: Uuid) -> Result<AppState, Error> {
+        let mut trash = self.trash.clone();
+        let index = trash
+            .iter()
+            .position(|trashed| trashed.entry.id == id)
+            .ok_or(Error::EntryNotFound(id))?;
+        let entry = trash.remove(index).entry;
+        let mut otp_entries = self.otp_entries.clone();
+        let position = otp_entries
+            .iter()
+            .position(OtpEntry::is_managed)
+            .unwrap_or(otp_entries.len());
+        otp_entries.insert(position, entry);
+        Ok(Self {
+            otp_entries,
+            trash,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Restore whichever entries were removed last, or return `None`
+    /// if the trash is empty and there's nothing to undo.
+    pub fn undo_remove(&self) -> Result<Option<AppState>, Error> {
+        let last_deleted_at = match self.trash.iter().map(|trashed| trashed.deleted_at).max() {
+            Some(last_deleted_at) => last_deleted_at,
+            None => return Ok(None),
+        };
+        self.trash
+            .iter()
+            .filter(|trashed| trashed.deleted_at == last_deleted_at)
+            .try_fold(self.clone(), |app_state, trashed| {
+                app_state.restore_entry(trashed.entry.id)
+            })
+            .map(Some)
+    }
+
+    /// Delete an entry from the trash for good.
+    pub fn purge_entry(&self, id: Uuid) -> Result<AppState, Error> {
+        if !self.trash.iter().any(|trashed| trashed.entry.id == id) {
+            return Err(Error::EntryNotFound(id));
         }
+        Ok(Self {
+            trash: self
+                .trash
+                .iter()
+                .filter(|trashed| trashed.entry.id != id)
+                .cloned()
+                .collect(),
+            ..self.clone()
+        })
     }
 
-    pub fn menu_reset(&self) -> Self {
+    pub fn trash_retention(&self) -> chrono::Duration {
+        chrono::Duration::days(
+            self.trash_retention_days
+                .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS) as i64,
+        )
+    }
+
+    /// Drop trashed entries older than the retention period. They are
+    /// gone from disk the next time the config is saved.
+    pub fn empty_expired_trash(self, now: DateTime<Utc>) -> Self {
+        let retention = self.trash_retention();
         Self {
-            otp_entries: self.otp_entries.clone(),
-            ..Default::default()
+            trash: self
+                .trash
+                .iter()
+                .filter(|trashed| trashed.deleted_at + retention > now)
+                .cloned()
+                .collect(),
+            ..self
         }
     }
-}
 
-#[derive(Debug)]
-pub enum UiEvent {
-    TotpRefresh,
-    OpenSetup,
-    OpenEntry(EntryAction),
-    SaveEntry(OtpEntry, EntryAction),
-    RemoveEntry(usize),
-    CopyToClipboard(u64),
-    Quit,
-}
+    /// Move an entry `offset` places up or down the list, stopping at
+    /// either end of the user's own entries.
+    pub fn move_entry_by(&self, id: Uuid, offset: isize) -> Result<AppState, Error> {
+        let index = self.entry_position(id)? as isize;
+        self.reorder_entry(id, (index + offset).max(0) as usize)
+    }
 
-#[derive(Debug, Clone)]
-pub enum ValidationError {
-    Empty {
-        field: &'static str,
-    },
-    IntegerFormat(std::num::ParseIntError),
-    Length {
-        field: &'static str,
-        upper_bound: usize,
-        length: usize,
-    },
-    InvalidSelection {
-        field: &'static str,
-        candidate: String,
-        valid_selections: &'static [&'static str],
-    },
-}
+    /// Move an entry to where `target_id` is in the list, shifting the
+    /// target and everything after it along by one.
+    pub fn move_entry_to(&self, id: Uuid, target_id: Uuid) -> Result<AppState, Error> {
+        self.reorder_entry(id, self.entry_position(target_id)?)
+    }
 
-impl From<std::num::ParseIntError> for ValidationError {
-    fn from(err: std::num::ParseIntError) -> Self {
-        ValidationError::IntegerFormat(err)
+    fn reorder_entry(&self, id: Uuid, index: usize) -> Result<AppState, Error> {
+        let position = self.entry_position(id)?;
+        let mut otp_entries = self.otp_entries.clone();
+        if otp_entries[position].is_managed() {
+            return Err(Error::ManagedEntry);
+        }
+        // Managed entries always come after the user's own.
+        let user_entry_count = otp_entries
+            .iter()
+            .filter(|entry| !entry.is_managed())
+            .count();
+        let entry = otp_entries.remove(position);
+        otp_entries.insert(index.min(user_entry_count - 1), entry);
+        Ok(Self {
+            otp_entries,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
     }
-}
 
-#[derive(Debug)]
-pub enum Error {
-    NoUserConfigDir,
-    YAML(serde_yaml::Error),
-    Io(std::io::Error),
-}
+    /// Every group name, in menu order. Groups only named by an entry,
+    /// such as ones from managed drop-ins, come after the rest.
+    pub fn all_groups(&self) -> Vec<String> {
+        let mut groups = self.groups.clone();
+        for group in self
+            .otp_entries
+            .iter()
+            .filter_map(|entry| entry.group.as_ref())
+        {
+            if !groups.contains(group) {
+                groups.push(group.clone());
+            }
+        }
+        groups
+    }
 
-impl From<serde_yaml::Error> for Error {
-    fn from(err: serde_yaml::Error) -> Error {
-        Error::YAML(err)
+    /// Pinned entries, sorted by `menu_order`.
+    pub fn pinned_entries(&self) -> Vec<&OtpEntry> {
+        self.menu_sorted(
+            self.otp_entries
+                .iter()
+                .filter(|entry| entry.pinned)
+                .collect(),
+        )
     }
-}
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Error {
-        Error::Io(err)
+    /// The entries whose codes were copied most recently, newest first.
+    pub fn recently_used_entries(&self) -> Vec<&OtpEntry> {
+        let mut entries = self
+            .otp_entries
+            .iter()
+            .filter(|entry| entry.last_used_at.is_some())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| Reverse(entry.last_used_at));
+        entries.truncate(RECENTLY_USED_COUNT);
+        entries
     }
-}
 
-pub struct OtpValue {
-    pub name: String,
-    pub otp: String,
-}
+    /// Unpinned entries split into the ungrouped ones, and the ones in
+    /// each group in menu order. Entries are sorted by `menu_order`
+    /// within each, and empty groups are left out.
+    pub fn grouped_entries(&self) -> (Vec<&OtpEntry>, Vec<(String, Vec<&OtpEntry>)>) {
+        let unpinned = self
+            .otp_entries
+            .iter()
+            .filter(|entry| !entry.pinned)
+            .collect::<Vec<_>>();
+        let ungrouped = self.menu_sorted(
+            unpinned
+                .iter()
+                .filter(|entry| entry.group.is_none())
+                .copied()
+                .collect(),
+        );
+        let grouped = self
+            .all_groups()
+            .into_iter()
+            .map(|group| {
+                let entries = self.menu_sorted(
+                    unpinned
+                        .iter()
+                        .filter(|entry| entry.group.as_ref() == Some(&group))
+                        .copied()
+                        .collect(),
+                );
+                (group, entries)
+            })
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect();
+        (ungrouped, grouped)
+    }
 
-impl OtpValue {
+    fn menu_sorted<'a>(&self, mut entries: Vec<&'a OtpEntry>) -> Vec<&'a OtpEntry> {
+        match self.menu_order {
+            MenuOrder::Manual => {}
+            MenuOrder::Alphabetical => entries.sort_by_key(|entry| entry.name.to_lowercase()),
+            MenuOrder::MostUsed => entries.sort_by_key(|entry| Reverse(entry.use_count)),
+            MenuOrder::MostRecent => entries.sort_by_key(|entry| Reverse(entry.last_used_at)),
+        }
+        entries
+    }
+
+    pub fn set_menu_order(&self, menu_order: MenuOrder) -> AppState {
+        Self {
+            menu_order,
+            ..self.clone()
+        }
+    }
+
+    pub fn set_pinned(&self, id: Uuid, pinned: bool) -> Result<AppState, Error> {
+        let index = self.entry_position(id)?;
+        let mut otp_entries = self.otp_entries.clone();
+        if otp_entries[index].is_managed() {
+            return Err(Error::ManagedEntry);
+        }
+        otp_entries[index].pinned = pinned;
+        Ok(Self {
+            otp_entries,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    pub fn create_group(&self, name: &str) -> Result<AppState, Error> {
+        let name = self.validate_new_group_name(name)?;
+        let mut groups = self.groups.clone();
+        groups.push(name);
+        Ok(Self {
+            groups,
+            ..self.clone()
+        })
+    }
+
+    /// Rename a group, moving the user's entries along with it.
+    /// Managed entries keep the group their drop-in file gives them.
+    pub fn rename_group(&self, name: &str, new_name: &str) -> Result<AppState, Error> {
+        self.check_group_exists(name)?;
+        let new_name = self.validate_new_group_name(new_name)?;
+        // Only the user's own list, so that groups named by managed
+        // entries don't get copied into it.
+        let mut groups = self.groups.clone();
+        for group in groups.iter_mut().filter(|group| *group == name) {
+            *group = new_name.clone();
+        }
+        let mut otp_entries = self.otp_entries.clone();
+        for entry in otp_entries.iter_mut().filter(|entry| !entry.is_managed()) {
+            if entry.group.as_deref() == Some(name) {
+                entry.group = Some(new_name.clone());
+            }
+        }
+        Ok(Self {
+            otp_entries,
+            groups,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Move a group `offset` places up or down the menu. Groups only
+    /// named by managed entries can't be moved, since their place comes
+    /// from the drop-in files.
+    pub fn move_group_by(&self, name: &str, offset: isize) -> Result<AppState, Error> {
+        self.check_group_exists(name)?;
+        let mut groups = self
+            .all_groups()
+            .into_iter()
+            .filter(|group| {
+                self.groups.contains(group)
+                    || self
+                        .otp_entries
+                        .iter()
+                        .any(|entry| !entry.is_managed() && entry.group.as_ref() == Some(group))
+            })
+            .collect::<Vec<_>>();
+        let index = groups
+            .iter()
+            .position(|group| group == name)
+            .ok_or(Error::ManagedEntry)?;
+        let group = groups.remove(index);
+        let new_index = (index as isize + offset).max(0) as usize;
+        groups.insert(new_index.min(groups.len()), group);
+        Ok(Self {
+            groups,
+            ..self.clone()
+        })
+    }
+
+    /// Delete a group. The user's entries in it become ungrouped
+    /// rather than being deleted with it.
+    pub fn delete_group(&self, name: &str) -> Result<AppState, Error> {
+        self.check_group_exists(name)?;
+        let groups = self
+            .groups
+            .iter()
+            .filter(|group| *group != name)
+            .cloned()
+            .collect();
+        let mut otp_entries = self.otp_entries.clone();
+        for entry in otp_entries.iter_mut().filter(|entry| !entry.is_managed()) {
+            if entry.group.as_deref() == Some(name) {
+                entry.group = None;
+            }
+        }
+        Ok(Self {
+            otp_entries,
+            groups,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    pub fn move_entry_to_group(&self, id: Uuid, group: Option<String>) -> Result<AppState, Error> {
+        self.move_entries_to_group(&[id], group)
+    }
+
+    pub fn move_entries_to_group(
+        &self,
+        ids: &[Uuid],
+        group: Option<String>,
+    ) -> Result<AppState, Error> {
+        if let Some(ref group) = group {
+            self.check_group_exists(group)?;
+        }
+        self.update_entries(ids, |entry| entry.group = group.clone())
+    }
+
+    /// Apply `edit` to every entry in `ids`.
+    pub fn bulk_edit(&self, ids: &[Uuid], edit: &BulkEdit) -> Result<AppState, Error> {
+        self.update_entries(ids, |entry| edit.apply(entry))
+    }
+
+    fn update_entries<F: Fn(&mut OtpEntry)>(
+        &self,
+        ids: &[Uuid],
+        update: F,
+    ) -> Result<AppState, Error> {
+        let mut otp_entries = self.otp_entries.clone();
+        for id in ids {
+            let entry = otp_entries
+                .iter_mut()
+                .find(|entry| entry.id == *id)
+                .ok_or(Error::EntryNotFound(*id))?;
+            if entry.is_managed() {
+                return Err(Error::ManagedEntry);
+            }
+            update(entry);
+        }
+        Ok(Self {
+            otp_entries,
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Write the entries in `ids` to a new config file at `path`,
+    /// secrets included. Like the config itself, only the current
+    /// user can read it.
+    pub fn export_entries(&self, ids: &[Uuid], path: &Path) -> Result<(), Error> {
+        if let Some(id) = ids.iter().find(|id| self.get_entry(**id).is_none()) {
+            return Err(Error::EntryNotFound(*id));
+        }
+        let config = OtpTrayConfig {
+            version: CONFIG_VERSION,
+            entries: self
+                .otp_entries
+                .iter()
+                .filter(|entry| ids.contains(&entry.id))
+                .cloned()
+                .collect(),
+            groups: Vec::new(),
+            idle_lock_seconds: None,
+            menu_label_format: None,
+            menu_order: MenuOrder::Manual,
+            trash: Vec::new(),
+            trash_retention_days: None,
+        };
+        let contents = Zeroizing::new(serde_yaml::to_string(&config)?);
+        write_private_file(path, contents.as_bytes())
+    }
+
+    fn check_group_exists(&self, name: &str) -> Result<(), Error> {
+        if self.all_groups().iter().any(|group| group == name) {
+            Ok(())
+        } else {
+            Err(Error::GroupNotFound(name.to_string()))
+        }
+    }
+
+    fn validate_new_group_name(&self, name: &str) -> Result<String, Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::InvalidGroupName(name.to_string()));
+        }
+        if self.all_groups().iter().any(|group| group == name) {
+            return Err(Error::GroupExists(name.to_string()));
+        }
+        Ok(name.to_string())
+    }
+
+    pub fn menu_reset(&self) -> Self {
+        Self {
+            otp_codes: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Drop every entry and generated code from the state. The
+    /// entries stay on disk, and are only brought back by reloading
+    /// the config on unlock.
+    pub fn lock(&self) -> Self {
+        Self {
+            otp_entries: Vec::new(),
+            trash: Vec::new(),
+            otp_codes: HashMap::new(),
+            locked: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn idle_expired(&self, idle_for: Duration) -> bool {
+        match self.idle_lock_seconds {
+            Some(seconds) if !self.locked => idle_for >= Duration::from_secs(seconds),
+            _ => false,
+        }
+    }
+
+    /// Answer a request from the control socket. Requests that only
+    /// read the state are answered here, the rest also return the event
+    /// the running tray carries them out with.
+    pub fn handle_control_request(
+        &self,
+        request: &ControlRequest,
+    ) -> Result<(ControlResponse, Option<UiEvent>), Error> {
+        match request {
+            ControlRequest::OpenSetup => Ok((ControlResponse::Done, Some(UiEvent::OpenSetup))),
+            ControlRequest::Lock => Ok((ControlResponse::Done, Some(UiEvent::Lock))),
+            _ if self.locked => Err(Error::Locked),
+            ControlRequest::Reload => Ok((ControlResponse::Done, Some(UiEvent::Reload))),
+            ControlRequest::List => Ok((
+                ControlResponse::Entries {
+                    entries: self.otp_entries.iter().map(ControlEntry::from).collect(),
+                },
+                None,
+            )),
+            ControlRequest::Code(selector) => {
+                let entry = self.select_entry(selector)?;
+                Ok((
+                    ControlResponse::Code {
+                        id: entry.id,
+                        name: entry.name.clone(),
+                        code: entry.get_otp_value().otp,
+                        expires_in: entry.seconds_remaining(),
+                    },
+                    None,
+                ))
+            }
+            ControlRequest::Copy(selector) => Ok((
+                ControlResponse::Done,
+                Some(UiEvent::CopyEntry(self.select_entry(selector)?.id)),
+            )),
+        }
+    }
+
+    fn select_entry(&self, selector: &EntrySelector) -> Result<&OtpEntry, Error> {
+        match selector {
+            EntrySelector::Id { id } => self.get_entry(*id).ok_or(Error::EntryNotFound(*id)),
+            EntrySelector::Name { name } => self.find_entry_by_name(name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UiEvent {
+    TotpRefresh,
+    OpenSetup,
+    OpenEntry(EntryAction),
+    SaveEntry(Box<OtpEntry>, EntryAction),
+    ConfirmRemoveEntries(Vec<Uuid>),
+    RemoveEntries(Vec<Uuid>),
+    UndoRemove,
+    RestoreEntry(Uuid),
+    ConfirmPurgeEntry(Uuid),
+    PurgeEntry(Uuid),
+    OpenGroup(GroupAction),
+    CreateGroup(String),
+    RenameGroup(String, String),
+    DeleteGroup(String),
+    MoveGroupBy(String, isize),
+    MoveToGroup(Vec<Uuid>, Option<String>),
+    OpenBulkEdit(Vec<Uuid>),
+    BulkEditEntries(Vec<Uuid>, BulkEdit),
+    ExportEntries(Vec<Uuid>, PathBuf),
+    TogglePinned(Uuid),
+    MoveEntryBy(Uuid, isize),
+    MoveEntryTo(Uuid, Uuid),
+    SetMenuOrder(MenuOrder),
+    CopyToClipboard(u64),
+    CopyEntry(Uuid),
+    RestoreBackup(PathBuf),
+    SwitchProfile(Option<String>),
+    ConfigChanged,
+    Reload,
+    Lock,
+    Unlock,
+    Quit,
+}
+
+/// A request on the control socket, one JSON object per line, like
+/// `{"command": "code", "name": "github"}`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    List,
+    Code(EntrySelector),
+    Copy(EntrySelector),
+    #[serde(rename = "setup")]
+    OpenSetup,
+    Reload,
+    Lock,
+}
+
+/// Entries are picked by the id from `list`, or looked up by name
+/// like on the command line.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EntrySelector {
+    Id { id: Uuid },
+    Name { name: String },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Entries {
+        entries: Vec<ControlEntry>,
+    },
+    Code {
+        id: Uuid,
+        name: String,
+        code: String,
+        /// Seconds until the code changes.
+        expires_in: u64,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+/// What `list` returns for each entry. Secrets are left out.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ControlEntry {
+    pub id: Uuid,
+    pub name: String,
+    pub issuer: Option<String>,
+    pub account: Option<String>,
+    pub group: Option<String>,
+    pub tags: Vec<String>,
+    pub step: u64,
+    pub digit_count: u32,
+}
+
+impl From<&OtpEntry> for ControlEntry {
+    fn from(entry: &OtpEntry) -> Self {
+        ControlEntry {
+            id: entry.id,
+            name: entry.name.clone(),
+            issuer: entry.issuer.clone(),
+            account: entry.account.clone(),
+            group: entry.group.clone(),
+            tags: entry.tags.clone(),
+            step: entry.step,
+            digit_count: entry.digit_count,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ValidationError {
+    Empty {
+        field: &'static str,
+    },
+    IntegerFormat(std::num::ParseIntError),
+    Length {
+        field: &'static str,
+        upper_bound: usize,
+        length: usize,
+    },
+    InvalidSelection {
+        field: &'static str,
+        candidate: String,
+        valid_selections: &'static [&'static str],
+    },
+}
+
+impl From<std::num::ParseIntError> for ValidationError {
+    fn from(err: std::num::ParseIntError) -> Self {
+        ValidationError::IntegerFormat(err)
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Empty { field } => write!(f, "{} can't be empty", field),
+            ValidationError::IntegerFormat(err) => write!(f, "expected a number: {}", err),
+            ValidationError::Length {
+                field,
+                upper_bound,
+                length,
+            } => write!(
+                f,
+                "{} is {} characters long, at most {} are allowed",
+                field, length, upper_bound
+            ),
+            ValidationError::InvalidSelection {
+                field,
+                candidate,
+                valid_selections,
+            } => write!(
+                f,
+                "{:?} isn't a valid {}, expected one of: {}",
+                candidate,
+                field,
+                valid_selections.join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NoUserConfigDir,
+    Conflict,
+    UnsupportedVersion {
+        version: u32,
+    },
+    InvalidProfileName(String),
+    ManagedEntry,
+    EntryNotFound(Uuid),
+    InvalidGroupName(String),
+    GroupExists(String),
+    GroupNotFound(String),
+    NoMatchingEntry(String),
+    AmbiguousEntry {
+        query: String,
+        candidates: Vec<String>,
+    },
+    InvalidEntry(ValidationError),
+    Locked,
+    OtherConfigRunning(PathBuf),
+    YAML(serde_yaml::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoUserConfigDir => write!(f, "no config directory for this user"),
+            Error::Conflict => write!(f, "the config file changed on disk, try again"),
+            Error::UnsupportedVersion { version } => write!(
+                f,
+                "the config file is version {}, newer than this otptray supports",
+                version
+            ),
+            Error::InvalidProfileName(name) => write!(f, "invalid profile name: {}", name),
+            Error::ManagedEntry => write!(f, "managed entries are read-only"),
+            Error::EntryNotFound(id) => write!(f, "no entry with id {}", id),
+            Error::InvalidGroupName(name) => write!(f, "invalid group name: {:?}", name),
+            Error::GroupExists(name) => write!(f, "group {} already exists", name),
+            Error::GroupNotFound(name) => write!(f, "no group named {}", name),
+            Error::NoMatchingEntry(query) => write!(f, "no entry matches {:?}", query),
+            Error::AmbiguousEntry { query, candidates } => write!(
+                f,
+                "{:?} matches several entries: {}",
+                query,
+                candidates.join(", ")
+            ),
+            Error::InvalidEntry(err) => write!(f, "invalid entry: {}", err),
+            Error::Locked => write!(f, "otptray is locked"),
+            Error::OtherConfigRunning(path) => {
+                write!(f, "the running otptray uses {}", path.display())
+            }
+            Error::YAML(err) => write!(f, "invalid config file: {}", err),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(err: ValidationError) -> Error {
+        Error::InvalidEntry(err)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Error {
+        Error::YAML(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Which config file otptray reads and writes. That's either the
+/// default profile at `$XDG_CONFIG_HOME/otptray/config.yaml`, unless
+/// `--config` points somewhere else, or a named profile in
+/// `$XDG_CONFIG_HOME/otptray/profiles/<name>.yaml`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigLocation {
+    pub config_override: Option<PathBuf>,
+    pub profile: Option<String>,
+    /// Stand in for the platform's user config dir and for
+    /// `/etc/otptray.d`, so tests never touch the real ones.
+    user_config_dir: Option<PathBuf>,
+    system_drop_in_dir: Option<PathBuf>,
+}
+
+impl ConfigLocation {
+    /// The location picked by `--config` and `--profile`, with
+    /// `$OTPTRAY_CONFIG` standing in for a missing `--config`.
+    pub fn from_env(config_arg: Option<PathBuf>, profile: Option<String>) -> Result<Self, Error> {
+        Self::from_vars(config_arg, profile, |name| env::var_os(name))
+    }
+
+    fn from_vars(
+        config_arg: Option<PathBuf>,
+        profile: Option<String>,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, Error> {
+        let config_override = config_arg.or_else(|| {
+            var(CONFIG_ENV_VAR)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        });
+        Self::new(config_override, profile)
+    }
+
+    pub fn new(config_override: Option<PathBuf>, profile: Option<String>) -> Result<Self, Error> {
+        Ok(Self {
+            config_override,
+            profile: Self::validate_profile(profile)?,
+            user_config_dir: None,
+            system_drop_in_dir: None,
+        })
+    }
+
+    /// The default profile, with the user config dir and the system
+    /// drop-in dir both under `dir`.
+    #[cfg(test)]
+    pub fn under_dir(dir: &Path) -> Self {
+        ConfigLocation {
+            config_override: None,
+            profile: None,
+            user_config_dir: Some(dir.to_path_buf()),
+            system_drop_in_dir: Some(dir.join("etc").join("otptray.d")),
+        }
+    }
+
+    pub fn with_profile(&self, profile: Option<String>) -> Result<Self, Error> {
+        Ok(Self {
+            profile: Self::validate_profile(profile)?,
+            ..self.clone()
+        })
+    }
+
+    fn validate_profile(profile: Option<String>) -> Result<Option<String>, Error> {
+        match profile {
+            Some(name) if name == DEFAULT_PROFILE => Ok(None),
+            Some(name) => {
+                if name.is_empty()
+                    || name.starts_with('.')
+                    || name.contains('/')
+                    || name.contains('\\')
+                {
+                    return Err(Error::InvalidProfileName(name));
+                }
+                Ok(Some(name))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The platform's user config dir, like `$XDG_CONFIG_HOME`.
+    fn user_config_dir(&self) -> Result<PathBuf, Error> {
+        match &self.user_config_dir {
+            Some(user_config_dir) => Ok(user_config_dir.clone()),
+            None => dirs::config_dir().ok_or(Error::NoUserConfigDir),
+        }
+    }
+
+    pub fn config_dir(&self) -> Result<PathBuf, Error> {
+        Ok(self.user_config_dir()?.join("otptray"))
+    }
+
+    pub fn config_path(&self) -> Result<PathBuf, Error> {
+        match (&self.profile, &self.config_override) {
+            (Some(profile), _) => Ok(self
+                .config_dir()?
+                .join("profiles")
+                .join(format!("{}.yaml", profile))),
+            (None, Some(config_override)) => Ok(config_override.clone()),
+            (None, None) => Ok(self.config_dir()?.join("config.yaml")),
+        }
+    }
+
+    pub fn profile_name(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// Every named profile with a config file, sorted by name. The
+    /// default profile is not included.
+    pub fn profiles(&self) -> Result<Vec<String>, Error> {
+        let profiles_dir = self.config_dir()?.join("profiles");
+        let read_dir = match fs::read_dir(&profiles_dir) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            other => other?,
+        };
+        let mut profiles = Vec::new();
+        for dir_entry in read_dir {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("yaml") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                if name != DEFAULT_PROFILE && !name.starts_with('.') {
+                    profiles.push(name.to_string());
+                }
+            }
+        }
+        profiles.sort();
+        Ok(profiles)
+    }
+
+    /// Backups of a profile are kept under the config directory.
+    /// Backups of a config given with `--config` are kept next to it,
+    /// so they're never mixed up with a profile's.
+    pub fn backup_dir(&self) -> Result<PathBuf, Error> {
+        match (&self.profile, &self.config_override) {
+            (None, Some(config_override)) => {
+                let file_name = config_override
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                Ok(config_override.with_file_name(format!(".{}.backups", file_name)))
+            }
+            _ => Ok(self.config_dir()?.join("backups").join(self.profile_name())),
+        }
+    }
+
+    fn write_backup(&self, contents: &[u8]) -> Result<(), Error> {
+        let backup_dir = self.backup_dir()?;
+        create_private_dir(&backup_dir)?;
+        let backup_name = format!(
+            "otptray-{}.yaml",
+            Local::now().format(BACKUP_TIMESTAMP_FORMAT)
+        );
+        let mut backup_file = private_file_options()
+            .create(true)
+            .truncate(true)
+            .open(backup_dir.join(backup_name))?;
+        backup_file.write_all(contents)?;
+        backup_file.sync_all()?;
+
+        for stale_backup in self.list_backups()?.into_iter().skip(BACKUP_COUNT) {
+            fs::remove_file(stale_backup.path)?;
+        }
+        Ok(())
+    }
+
+    /// All config backups for this profile, newest first.
+    pub fn list_backups(&self) -> Result<Vec<ConfigBackup>, Error> {
+        let read_dir = match fs::read_dir(self.backup_dir()?) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            other => other?,
+        };
+        let mut backups = Vec::new();
+        for dir_entry in read_dir {
+            let path = dir_entry?.path();
+            let created_at = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("otptray-"))
+                .and_then(|name| name.strip_suffix(".yaml"))
+                .and_then(|timestamp| {
+                    NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP_FORMAT).ok()
+                });
+            if let Some(created_at) = created_at {
+                backups.push(ConfigBackup { path, created_at });
+            }
+        }
+        backups.sort_by_key(|backup| Reverse(backup.created_at));
+        Ok(backups)
+    }
+
+    /// Directories whose `*.yaml` files hold managed entries: one for
+    /// the whole system, and one for the current user.
+    pub fn drop_in_dirs(&self) -> Result<Vec<PathBuf>, Error> {
+        Ok(vec![
+            self.system_drop_in_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(SYSTEM_DROP_IN_DIR)),
+            self.config_dir()?.join("conf.d"),
+        ])
+    }
+
+    /// Load the entries from every drop-in file, in directory order,
+    /// then by file name. A broken drop-in file is skipped rather than
+    /// keeping the user's own entries from loading.
+    fn managed_entries(&self) -> Result<Vec<OtpEntry>, Error> {
+        let mut managed_entries = Vec::new();
+        for drop_in_dir in self.drop_in_dirs()? {
+            let read_dir = match fs::read_dir(&drop_in_dir) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                other => other?,
+            };
+            let mut drop_in_paths = read_dir
+                .filter_map(|dir_entry| dir_entry.ok().map(|dir_entry| dir_entry.path()))
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("yaml"))
+                .collect::<Vec<_>>();
+            drop_in_paths.sort();
+
+            for drop_in_path in drop_in_paths {
+                match AppState::load_from_path(&drop_in_path) {
+                    Ok(drop_in) => {
+                        managed_entries.extend(drop_in.otp_entries.into_iter().map(|entry| {
+                            OtpEntry {
+                                id: match entry.id.is_nil() {
+                                    true => managed_entry_id(&drop_in_path, &entry.name),
+                                    false => entry.id,
+                                },
+                                source: EntrySource::Managed(drop_in_path.clone()),
+                                ..entry
+                            }
+                        }))
+                    }
+                    Err(err) => log::error!(
+                        "Skipping drop-in file {:?}, failed to load: {:?}",
+                        drop_in_path,
+                        err
+                    ),
+                }
+            }
+        }
+        Ok(managed_entries)
+    }
+
+    /// The config file of older otptray versions, if there is one that
+    /// hasn't been moved to `config_path` yet.
+    fn legacy_config_path(&self) -> Result<Option<PathBuf>, Error> {
+        if self.profile.is_some() || self.config_override.is_some() {
+            return Ok(None);
+        }
+        let legacy_path = self.user_config_dir()?.join("otptray.yaml");
+        if self.config_path()?.exists() || !legacy_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(legacy_path))
+    }
+
+    /// Older otptray versions kept `otptray.yaml` and `otptray-backups/`
+    /// directly in the user config dir. Move them into the `otptray/`
+    /// dir, unless the default profile was already set up there.
+    fn migrate_legacy_layout(&self) -> Result<(), Error> {
+        let legacy_path = match self.legacy_config_path()? {
+            Some(legacy_path) => legacy_path,
+            None => return Ok(()),
+        };
+        let config_path = self.config_path()?;
+
+        log::info!("Moving {:?} to {:?}", legacy_path, config_path);
+        create_private_dir(&self.config_dir()?)?;
+        fs::rename(&legacy_path, &config_path)?;
+
+        let legacy_backup_dir = self.user_config_dir()?.join("otptray-backups");
+        let backup_dir = self.backup_dir()?;
+        if legacy_backup_dir.is_dir() && !backup_dir.exists() {
+            if let Some(backups_root) = backup_dir.parent() {
+                create_private_dir(backups_root)?;
+            }
+            fs::rename(&legacy_backup_dir, &backup_dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry in the trash, along with when it was removed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrashedEntry {
+    pub deleted_at: DateTime<Utc>,
+    pub entry: OtpEntry,
+}
+
+impl TrashedEntry {
+    pub fn formatted_display(&self, retention: chrono::Duration) -> String {
+        format!(
+            "Removed {}, deleted for good after {}",
+            self.deleted_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M"),
+            (self.deleted_at + retention)
+                .with_timezone(&Local)
+                .format("%Y-%m-%d")
+        )
+    }
+}
+
+pub struct ConfigBackup {
+    pub path: PathBuf,
+    pub created_at: NaiveDateTime,
+}
+
+impl ConfigBackup {
+    pub fn formatted_display(&self) -> String {
+        self.created_at.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+fn private_file_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// Write `contents` to `path`, readable only by the current user,
+/// for files that hold secrets outside of the config.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut file = private_file_options()
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    // The mode only applies to new files, so tighten up any file
+    // being overwritten as well.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn create_private_dir(path: &Path) -> Result<(), Error> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)?;
+    Ok(())
+}
+
+fn read_config_file(path: &Path) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(Zeroizing::new(contents))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn config_digest(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, and
+/// `:` which separates the issuer in otpauth labels.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Give entries without an id a fresh one, and likewise entries whose
+/// id an earlier entry already has, so a copied and pasted entry can't
+/// shadow the original. Returns whether any entry got one.
+fn assign_entry_ids<'a>(entries: impl Iterator<Item = &'a mut OtpEntry>) -> bool {
+    let mut seen = HashSet::new();
+    let mut assigned = false;
+    for entry in entries {
+        if entry.id.is_nil() || !seen.insert(entry.id) {
+            if !entry.id.is_nil() {
+                log::warn!(
+                    "Entry {} has a duplicate id, assigning a new one",
+                    entry.name
+                );
+            }
+            entry.id = Uuid::new_v4();
+            seen.insert(entry.id);
+            assigned = true;
+        }
+    }
+    assigned
+}
+
+/// Managed entries are read-only, so an id given to one can't be saved.
+/// Deriving it from where the entry comes from keeps it the same
+/// across reloads instead.
+fn managed_entry_id(drop_in_path: &Path, name: &str) -> Uuid {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("file://{}#{}", drop_in_path.display(), name).as_bytes(),
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct MenuCode {
+    pub entry_id: Uuid,
+    pub otp: String,
+}
+
+pub struct OtpValue {
+    pub name: String,
+    pub otp: String,
+}
+
+impl OtpValue {
     pub fn formatted_menu_display(&self) -> String {
         format!("{}: {}", self.name, self.otp)
     }
@@ -297,7 +2107,52 @@ impl OtpValue {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OtpTrayConfig {
+    #[serde(default)]
+    version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
     entries: Vec<OtpEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_lock_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    menu_label_format: Option<String>,
+    #[serde(default, skip_serializing_if = "MenuOrder::is_manual")]
+    menu_order: MenuOrder,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trash: Vec<TrashedEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trash_retention_days: Option<u64>,
+}
+
+impl OtpTrayConfig {
+    /// Parse a config of any version, running it through each
+    /// migration it is missing. Also returns the version the config
+    /// was written as. Configs from a newer otptray are read as-is,
+    /// ignoring any fields this build doesn't know about.
+    fn parse(contents: &[u8]) -> Result<(Self, u32), Error> {
+        let mut document: serde_yaml::Value = serde_yaml::from_slice(contents)?;
+        let version = document
+            .get("version")
+            .and_then(serde_yaml::Value::as_u64)
+            .unwrap_or(0) as u32;
+        for migration in CONFIG_MIGRATIONS.iter().skip(version as usize) {
+            migration(&mut document)?;
+        }
+        let config = serde_yaml::from_value(document)?;
+        Ok((config, version))
+    }
+}
+
+type ConfigMigration = fn(&mut serde_yaml::Value) -> Result<(), Error>;
+
+/// The migration at index `n` upgrades a version `n` config document
+/// to version `n + 1`.
+static CONFIG_MIGRATIONS: &[ConfigMigration] = &[migrate_v0_to_v1];
+
+/// Version 0 configs are the ones written before the `version` key
+/// existed. Their layout is otherwise the same as version 1.
+fn migrate_v0_to_v1(_document: &mut serde_yaml::Value) -> Result<(), Error> {
+    Ok(())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -305,3 +2160,818 @@ pub enum ActivationPolicy {
     Foreground,
     Background,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+
+    /// An entry as written by hand, without an id.
+    const GITHUB_ENTRY: &str = "  - name: GitHub
+    step: 30
+    secret_hash: JBSWY3DPEHPK3PXP
+    hash_fn: sha1
+    digit_count: 6
+";
+
+    fn entry(name: &str) -> OtpEntry {
+        OtpEntry {
+            name: name.to_string(),
+            secret_hash: Secret::new("JBSWY3DPEHPK3PXP".to_string()),
+            ..OtpEntry::default()
+        }
+    }
+
+    /// Also used by the D-Bus tests.
+    pub(crate) fn app_state(names: &[&str]) -> AppState {
+        names.iter().fold(AppState::default(), |app_state, name| {
+            app_state.save_entry(entry(name), EntryAction::Add).unwrap()
+        })
+    }
+
+    fn names(app_state: &AppState) -> Vec<&str> {
+        app_state
+            .otp_entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect()
+    }
+
+    /// A config given with `--config`, in a new temporary dir that
+    /// also stands in for the user config dir and `/etc/otptray.d`.
+    fn temp_config() -> (PathBuf, ConfigLocation) {
+        let config_dir = env::temp_dir().join(format!("otptray-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+        let config_location = ConfigLocation {
+            config_override: Some(config_dir.join("otptray.yaml")),
+            ..ConfigLocation::under_dir(&config_dir)
+        };
+        (config_dir, config_location)
+    }
+
+    /// The default profile, in a new temporary dir standing in for
+    /// the user config dir.
+    fn temp_user_config() -> (PathBuf, ConfigLocation) {
+        let user_config_dir = env::temp_dir().join(format!("otptray-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&user_config_dir).unwrap();
+        let config_location = ConfigLocation::under_dir(&user_config_dir);
+        (user_config_dir, config_location)
+    }
+
+    #[test]
+    fn config_path_resolution() {
+        let user_config_dir = PathBuf::from("/home/user/.config");
+        let config_path = |config_arg: Option<&str>, profile: Option<&str>, var: Option<&str>| {
+            ConfigLocation {
+                user_config_dir: Some(user_config_dir.clone()),
+                ..ConfigLocation::from_vars(
+                    config_arg.map(PathBuf::from),
+                    profile.map(String::from),
+                    |name| {
+                        assert_eq!(name, CONFIG_ENV_VAR);
+                        var.map(OsString::from)
+                    },
+                )
+                .unwrap()
+            }
+            .config_path()
+            .unwrap()
+        };
+        let default_path = user_config_dir.join("otptray").join("config.yaml");
+        assert_eq!(config_path(None, None, None), default_path);
+        assert_eq!(config_path(None, None, Some("")), default_path);
+        assert_eq!(config_path(None, Some(DEFAULT_PROFILE), None), default_path);
+        assert_eq!(
+            config_path(None, None, Some("/srv/from-env.yaml")),
+            PathBuf::from("/srv/from-env.yaml")
+        );
+        assert_eq!(
+            config_path(Some("from-arg.yaml"), None, Some("/srv/from-env.yaml")),
+            PathBuf::from("from-arg.yaml")
+        );
+        assert_eq!(
+            config_path(Some("from-arg.yaml"), Some("work"), None),
+            user_config_dir
+                .join("otptray")
+                .join("profiles")
+                .join("work.yaml")
+        );
+    }
+
+    #[test]
+    fn invalid_profile_names_are_refused() {
+        for name in &["", ".hidden", "../work", "work\\home"] {
+            assert!(matches!(
+                ConfigLocation::new(None, Some(name.to_string())),
+                Err(Error::InvalidProfileName(invalid)) if invalid == *name
+            ));
+        }
+    }
+
+    #[test]
+    fn switching_profiles_keeps_the_config_dirs() {
+        let (user_config_dir, config_location) = temp_user_config();
+        let _ = std::fs::remove_dir_all(&user_config_dir);
+        let work = config_location
+            .with_profile(Some("work".to_string()))
+            .unwrap();
+        assert_eq!(
+            work.config_path().unwrap(),
+            user_config_dir
+                .join("otptray")
+                .join("profiles")
+                .join("work.yaml")
+        );
+        assert_eq!(
+            work.backup_dir().unwrap(),
+            user_config_dir.join("otptray").join("backups").join("work")
+        );
+        assert_eq!(work.with_profile(None).unwrap(), config_location);
+    }
+
+    #[test]
+    fn config_override_backups_are_kept_next_to_it() {
+        let config_location =
+            ConfigLocation::new(Some(PathBuf::from("/srv/otptray/team.yaml")), None).unwrap();
+        assert_eq!(
+            config_location.backup_dir().unwrap(),
+            PathBuf::from("/srv/otptray/.team.yaml.backups")
+        );
+    }
+
+    #[test]
+    fn profiles_are_listed_by_name() {
+        let (user_config_dir, config_location) = temp_user_config();
+        let profiles_dir = user_config_dir.join("otptray").join("profiles");
+        std::fs::create_dir_all(&profiles_dir).unwrap();
+        for file_name in &["work.yaml", "home.yaml", ".hidden.yaml", "notes.txt"] {
+            std::fs::write(profiles_dir.join(file_name), "").unwrap();
+        }
+        let profiles = config_location.profiles().unwrap();
+        let _ = std::fs::remove_dir_all(&user_config_dir);
+
+        assert_eq!(profiles, ["home", "work"]);
+    }
+
+    #[test]
+    fn legacy_config_is_moved_into_the_config_dir() {
+        let (user_config_dir, config_location) = temp_user_config();
+        let contents = format!("---\nentries:\n{}", GITHUB_ENTRY);
+        std::fs::write(user_config_dir.join("otptray.yaml"), &contents).unwrap();
+        let legacy_backup_dir = user_config_dir.join("otptray-backups");
+        std::fs::create_dir_all(&legacy_backup_dir).unwrap();
+        std::fs::write(legacy_backup_dir.join("otptray-old.yaml"), "").unwrap();
+
+        config_location.migrate_legacy_layout().unwrap();
+        let on_disk = std::fs::read_to_string(config_location.config_path().unwrap()).unwrap();
+        let legacy_left = user_config_dir.join("otptray.yaml").exists();
+        let backup_moved = config_location
+            .backup_dir()
+            .unwrap()
+            .join("otptray-old.yaml")
+            .exists();
+        let _ = std::fs::remove_dir_all(&user_config_dir);
+
+        assert_eq!(on_disk, contents);
+        assert!(!legacy_left);
+        assert!(backup_moved);
+    }
+
+    #[test]
+    fn legacy_config_is_left_alone_once_the_config_dir_is_set_up() {
+        let (user_config_dir, config_location) = temp_user_config();
+        let legacy_path = user_config_dir.join("otptray.yaml");
+        std::fs::write(&legacy_path, "legacy").unwrap();
+        std::fs::create_dir_all(config_location.config_dir().unwrap()).unwrap();
+        std::fs::write(config_location.config_path().unwrap(), "current").unwrap();
+        let work = config_location
+            .with_profile(Some("work".to_string()))
+            .unwrap();
+
+        config_location.migrate_legacy_layout().unwrap();
+        work.migrate_legacy_layout().unwrap();
+        let on_disk = std::fs::read_to_string(config_location.config_path().unwrap()).unwrap();
+        let legacy_left = legacy_path.exists();
+        let work_created = work.config_path().unwrap().exists();
+        let _ = std::fs::remove_dir_all(&user_config_dir);
+
+        assert_eq!(on_disk, "current");
+        assert!(legacy_left);
+        assert!(!work_created);
+    }
+
+    #[test]
+    fn edit_after_removing_an_earlier_entry() {
+        let app_state = app_state(&["GitHub", "Google", "GitLab"]);
+        let gitlab_id = app_state.otp_entries[2].id;
+        let app_state = app_state
+            .remove_entry(app_state.otp_entries[0].id)
+            .unwrap()
+            .save_entry(entry("GitLab (work)"), EntryAction::Edit(gitlab_id))
+            .unwrap();
+        assert_eq!(names(&app_state), ["Google", "GitLab (work)"]);
+        assert_eq!(app_state.otp_entries[1].id, gitlab_id);
+    }
+
+    #[test]
+    fn edit_of_removed_entry_fails() {
+        // An editor that was opened before its entry was removed.
+        let app_state = app_state(&["GitHub", "Google"]);
+        let github_id = app_state.otp_entries[0].id;
+        let app_state = app_state.remove_entry(github_id).unwrap();
+        assert!(matches!(
+            app_state.save_entry(entry("GitHub (work)"), EntryAction::Edit(github_id)),
+            Err(Error::EntryNotFound(id)) if id == github_id
+        ));
+        assert_eq!(names(&app_state), ["Google"]);
+    }
+
+    #[test]
+    fn remove_twice_fails() {
+        let app_state = app_state(&["GitHub", "Google"]);
+        let github_id = app_state.otp_entries[0].id;
+        let app_state = app_state.remove_entry(github_id).unwrap();
+        assert!(matches!(
+            app_state.remove_entry(github_id),
+            Err(Error::EntryNotFound(_))
+        ));
+        assert_eq!(app_state.trash.len(), 1);
+    }
+
+    #[test]
+    fn edit_after_undoing_a_removal() {
+        let app_state = app_state(&["GitHub", "Google"]);
+        let github_id = app_state.otp_entries[0].id;
+        let app_state = app_state
+            .remove_entry(github_id)
+            .unwrap()
+            .undo_remove()
+            .unwrap()
+            .unwrap()
+            .save_entry(entry("GitHub (work)"), EntryAction::Edit(github_id))
+            .unwrap();
+        assert_eq!(names(&app_state), ["Google", "GitHub (work)"]);
+        assert!(app_state.trash.is_empty());
+    }
+
+    #[test]
+    fn undo_with_empty_trash_does_nothing() {
+        assert!(app_state(&["GitHub"]).undo_remove().unwrap().is_none());
+    }
+
+    #[test]
+    fn assigned_ids_are_kept_across_loads() {
+        let (config_dir, config_location) = temp_config();
+        std::fs::write(
+            config_location.config_path().unwrap(),
+            format!("---\nversion: 1\nentries:\n{}", GITHUB_ENTRY),
+        )
+        .unwrap();
+        let first = AppState::load_from_config(&config_location).unwrap();
+        let second = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        let id = |app_state: &AppState| {
+            app_state
+                .otp_entries
+                .iter()
+                .find(|entry| entry.name == "GitHub")
+                .unwrap()
+                .id
+        };
+        assert!(!id(&first).is_nil());
+        assert_eq!(id(&first), id(&second));
+    }
+
+    #[test]
+    fn saves_keep_the_newest_backups() {
+        let (config_dir, config_location) = temp_config();
+        let mut app_state = AppState {
+            config_location: config_location.clone(),
+            ..AppState::default()
+        };
+        for i in 0..=BACKUP_COUNT + 1 {
+            // Backups are named after the time they're made, down to
+            // the millisecond.
+            std::thread::sleep(Duration::from_millis(2));
+            app_state = app_state
+                .save_entry(entry(&format!("Entry {}", i)), EntryAction::Add)
+                .unwrap()
+                .save_to_config()
+                .unwrap();
+        }
+        let backups = config_location
+            .list_backups()
+            .unwrap()
+            .iter()
+            .map(|backup| std::fs::read_to_string(&backup.path).unwrap())
+            .collect::<Vec<_>>();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        // Every save but the first backed up the config it replaced.
+        assert_eq!(backups.len(), BACKUP_COUNT);
+        let newest = &backups[0];
+        assert!(newest.contains(&format!("Entry {}", BACKUP_COUNT)));
+        assert!(!newest.contains(&format!("Entry {}", BACKUP_COUNT + 1)));
+        let oldest = &backups[BACKUP_COUNT - 1];
+        assert!(oldest.contains("Entry 1"));
+        assert!(!oldest.contains("Entry 2"));
+    }
+
+    #[test]
+    fn usage_saves_keep_no_backup() {
+        let (config_dir, config_location) = temp_config();
+        let app_state = AppState {
+            config_location: config_location.clone(),
+            ..AppState::default()
+        }
+        .save_entry(entry("GitHub"), EntryAction::Add)
+        .unwrap()
+        .save_to_config()
+        .unwrap();
+        let github_id = app_state.otp_entries[0].id;
+        app_state
+            .mark_used(github_id)
+            .save_usage_to_config()
+            .unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let reloaded = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert!(backups.is_empty());
+        assert_eq!(reloaded.get_entry(github_id).unwrap().use_count, 1);
+    }
+
+    #[test]
+    fn failed_write_leaves_the_config_alone() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        let app_state = AppState {
+            config_location: config_location.clone(),
+            ..AppState::default()
+        }
+        .save_entry(entry("GitHub"), EntryAction::Add)
+        .unwrap()
+        .save_to_config()
+        .unwrap();
+        let contents = std::fs::read_to_string(&config_path).unwrap();
+        // Nothing can be written where the temporary file goes.
+        let temp_path = config_path.with_extension("yaml.tmp");
+        std::fs::create_dir(&temp_path).unwrap();
+        let saved = app_state
+            .save_entry(entry("Google"), EntryAction::Add)
+            .unwrap()
+            .save_to_config();
+        let on_disk = std::fs::read_to_string(&config_path).unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert!(matches!(saved, Err(Error::Io(_))));
+        assert_eq!(on_disk, contents);
+        assert!(backups.is_empty());
+    }
+
+    #[test]
+    fn outside_edits_are_never_overwritten() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        std::fs::write(
+            &config_path,
+            format!("---\nversion: 1\nentries:\n{}", GITHUB_ENTRY),
+        )
+        .unwrap();
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let github_id = app_state.otp_entries[0].id;
+        let unchanged_before_edit = app_state.config_unchanged().unwrap();
+        // Edited by hand while otptray had it loaded.
+        let edited = std::fs::read_to_string(&config_path)
+            .unwrap()
+            .replace("name: GitHub", "name: GitHub (work)");
+        std::fs::write(&config_path, &edited).unwrap();
+        let unchanged_after_edit = app_state.config_unchanged().unwrap();
+        let saved = app_state
+            .save_entry(entry("Google"), EntryAction::Add)
+            .unwrap()
+            .save_to_config();
+        let usage_saved = app_state.mark_used(github_id).save_usage_to_config();
+        let on_disk = std::fs::read_to_string(&config_path).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert!(unchanged_before_edit);
+        assert!(!unchanged_after_edit);
+        assert!(matches!(saved, Err(Error::Conflict)));
+        assert!(matches!(usage_saved, Err(Error::Conflict)));
+        assert_eq!(on_disk, edited);
+    }
+
+    #[test]
+    fn older_configs_are_upgraded_with_a_backup() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        let contents = format!("---\nentries:\n{}", GITHUB_ENTRY);
+        std::fs::write(&config_path, &contents).unwrap();
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let on_disk = std::fs::read_to_string(&config_path).unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let backed_up = std::fs::read_to_string(&backups[0].path).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(app_state.config_version, CONFIG_VERSION);
+        assert!(on_disk.contains(&format!("version: {}", CONFIG_VERSION)));
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backed_up, contents);
+    }
+
+    #[test]
+    fn newer_configs_are_loaded_but_never_saved() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        // Written by a newer otptray, with a field this one doesn't
+        // know about and an entry without an id.
+        let contents = format!(
+            "---\nversion: {}\nfuture_field: true\nentries:\n{}",
+            CONFIG_VERSION + 1,
+            GITHUB_ENTRY
+        );
+        std::fs::write(&config_path, &contents).unwrap();
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let saved = app_state
+            .save_entry(entry("Google"), EntryAction::Add)
+            .unwrap()
+            .save_to_config();
+        let on_disk = std::fs::read_to_string(&config_path).unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(names(&app_state), ["GitHub"]);
+        assert!(!app_state.otp_entries[0].id.is_nil());
+        assert!(matches!(
+            saved,
+            Err(Error::UnsupportedVersion { version }) if version == CONFIG_VERSION + 1
+        ));
+        assert_eq!(on_disk, contents);
+        assert!(backups.is_empty());
+    }
+
+    #[test]
+    fn every_older_version_has_a_migration() {
+        assert_eq!(CONFIG_MIGRATIONS.len(), CONFIG_VERSION as usize);
+    }
+
+    #[test]
+    fn version_0_configs_parse_as_they_are() {
+        let contents = format!("---\nentries:\n{}", GITHUB_ENTRY);
+        let mut document: serde_yaml::Value = serde_yaml::from_str(&contents).unwrap();
+        let original = document.clone();
+        migrate_v0_to_v1(&mut document).unwrap();
+        assert_eq!(document, original);
+
+        let (config, version) = OtpTrayConfig::parse(contents.as_bytes()).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.entries[0].name, "GitHub");
+        assert_eq!(config.entries[0].secret_hash.expose(), "JBSWY3DPEHPK3PXP");
+    }
+
+    fn grouped(name: &str, group: &str) -> OtpEntry {
+        OtpEntry {
+            group: Some(group.to_string()),
+            ..entry(name)
+        }
+    }
+
+    fn managed(name: &str, group: &str) -> OtpEntry {
+        OtpEntry {
+            id: Uuid::new_v4(),
+            source: EntrySource::Managed(PathBuf::from("/etc/otptray.d/shared.yaml")),
+            ..grouped(name, group)
+        }
+    }
+
+    #[test]
+    fn rename_group_leaves_managed_groups_out() {
+        let app_state = AppState {
+            groups: vec!["Work".to_string()],
+            otp_entries: vec![grouped("GitHub", "Work"), managed("Vault", "Shared")],
+            ..AppState::default()
+        };
+        let app_state = app_state.rename_group("Work", "Job").unwrap();
+        assert_eq!(app_state.groups, ["Job"]);
+        assert_eq!(app_state.all_groups(), ["Job", "Shared"]);
+    }
+
+    #[test]
+    fn move_group_by() {
+        let app_state = AppState {
+            groups: vec!["Work".to_string(), "Personal".to_string()],
+            otp_entries: vec![
+                grouped("GitHub", "Work"),
+                grouped("Steam", "Games"),
+                managed("Vault", "Shared"),
+            ],
+            ..AppState::default()
+        };
+        let moved = app_state.move_group_by("Games", -1).unwrap();
+        assert_eq!(moved.groups, ["Work", "Games", "Personal"]);
+        let moved = moved.move_group_by("Work", 5).unwrap();
+        assert_eq!(moved.all_groups(), ["Games", "Personal", "Work", "Shared"]);
+        let moved = moved.move_group_by("Personal", -5).unwrap();
+        assert_eq!(moved.groups, ["Personal", "Games", "Work"]);
+        assert!(matches!(
+            app_state.move_group_by("Shared", -1),
+            Err(Error::ManagedEntry)
+        ));
+    }
+
+    #[test]
+    fn read_only_load_leaves_the_config_alone() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        let contents = format!("---\nentries:\n{}", GITHUB_ENTRY);
+        std::fs::write(&config_path, &contents).unwrap();
+        let app_state = AppState::load_read_only(&config_location).unwrap();
+        let on_disk = std::fs::read_to_string(&config_path).unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(on_disk, contents);
+        assert!(backups.is_empty());
+        assert!(!app_state.otp_entries[0].id.is_nil());
+    }
+
+    fn write_drop_in(drop_in_dir: &Path, file_name: &str, names: &[&str]) {
+        std::fs::create_dir_all(drop_in_dir).unwrap();
+        let entries = names
+            .iter()
+            .map(|name| GITHUB_ENTRY.replace("GitHub", name))
+            .collect::<String>();
+        std::fs::write(
+            drop_in_dir.join(file_name),
+            format!("---\nentries:\n{}", entries),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn drop_in_entries_follow_the_users_own() {
+        let (config_dir, config_location) = temp_config();
+        std::fs::write(
+            config_location.config_path().unwrap(),
+            format!("---\nversion: 1\nentries:\n{}", GITHUB_ENTRY),
+        )
+        .unwrap();
+        let drop_in_dirs = config_location.drop_in_dirs().unwrap();
+        write_drop_in(&drop_in_dirs[1], "a-team.yaml", &["Grafana"]);
+        write_drop_in(&drop_in_dirs[0], "b-shared.yaml", &["Vault"]);
+        write_drop_in(&drop_in_dirs[0], "a-shared.yaml", &["AWS", "Jenkins"]);
+        std::fs::write(drop_in_dirs[0].join("broken.yaml"), "entries: [").unwrap();
+        std::fs::write(drop_in_dirs[0].join("notes.txt"), "not yaml").unwrap();
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(
+            names(&app_state),
+            ["GitHub", "AWS", "Jenkins", "Vault", "Grafana"]
+        );
+        let sources = app_state
+            .otp_entries
+            .iter()
+            .map(|entry| entry.source.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                EntrySource::User,
+                EntrySource::Managed(drop_in_dirs[0].join("a-shared.yaml")),
+                EntrySource::Managed(drop_in_dirs[0].join("a-shared.yaml")),
+                EntrySource::Managed(drop_in_dirs[0].join("b-shared.yaml")),
+                EntrySource::Managed(drop_in_dirs[1].join("a-team.yaml")),
+            ]
+        );
+    }
+
+    #[test]
+    fn user_entries_keep_their_ids_over_drop_ins() {
+        let (config_dir, config_location) = temp_config();
+        let id = Uuid::new_v4();
+        let with_id = |name: &str| {
+            GITHUB_ENTRY.replace("GitHub", name).replacen(
+                "  - ",
+                &format!("  - id: {}\n    ", id),
+                1,
+            )
+        };
+        std::fs::write(
+            config_location.config_path().unwrap(),
+            format!("---\nversion: 1\nentries:\n{}", with_id("GitHub")),
+        )
+        .unwrap();
+        let drop_in_dir = config_location.drop_in_dirs().unwrap().remove(0);
+        std::fs::create_dir_all(&drop_in_dir).unwrap();
+        std::fs::write(
+            drop_in_dir.join("shared.yaml"),
+            format!("---\nentries:\n{}", with_id("Vault")),
+        )
+        .unwrap();
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(names(&app_state), ["GitHub", "Vault"]);
+        assert_eq!(app_state.otp_entries[0].id, id);
+        assert_ne!(app_state.otp_entries[1].id, id);
+    }
+
+    #[test]
+    fn managed_entries_are_read_only_and_never_saved() {
+        let (config_dir, config_location) = temp_config();
+        let drop_in_dir = config_location.drop_in_dirs().unwrap().remove(0);
+        write_drop_in(&drop_in_dir, "shared.yaml", &["Vault"]);
+        let app_state = AppState::load_from_config(&config_location).unwrap();
+        let vault_id = app_state.otp_entries[0].id;
+        let edited = app_state.save_entry(entry("Vault (mine)"), EntryAction::Edit(vault_id));
+        let removed = app_state.remove_entry(vault_id);
+        app_state
+            .save_entry(entry("GitHub"), EntryAction::Add)
+            .unwrap()
+            .save_to_config()
+            .unwrap();
+        let on_disk = std::fs::read_to_string(config_location.config_path().unwrap()).unwrap();
+        let reloaded = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert!(matches!(edited, Err(Error::ManagedEntry)));
+        assert!(matches!(removed, Err(Error::ManagedEntry)));
+        assert!(!on_disk.contains("Vault"));
+        assert_eq!(names(&reloaded), ["GitHub", "Vault"]);
+        assert!(reloaded.get_entry(vault_id).unwrap().is_managed());
+    }
+
+    #[test]
+    fn recording_a_use_upgrades_the_config_with_a_backup() {
+        let (config_dir, config_location) = temp_config();
+        let config_path = config_location.config_path().unwrap();
+        let contents = format!("---\nentries:\n{}", GITHUB_ENTRY);
+        std::fs::write(&config_path, &contents).unwrap();
+        let read_only = AppState::load_read_only(&config_location).unwrap();
+        let github_id = read_only.otp_entries[0].id;
+        let recorded = read_only.record_use(github_id).unwrap();
+        let backups = config_location.list_backups().unwrap();
+        let backed_up = std::fs::read_to_string(&backups[0].path).unwrap();
+        let reloaded = AppState::load_from_config(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backed_up, contents);
+        assert_eq!(reloaded.config_version, CONFIG_VERSION);
+        // The id was only given in memory, and the upgrade gave the
+        // entry another one.
+        assert_ne!(recorded.otp_entries[0].id, github_id);
+        assert_eq!(reloaded.otp_entries[0].use_count, 1);
+    }
+
+    #[test]
+    fn recording_a_use_saves_it() {
+        let (config_dir, config_location) = temp_config();
+        AppState {
+            config_location: config_location.clone(),
+            ..AppState::default()
+        }
+        .save_entry(entry("GitHub"), EntryAction::Add)
+        .unwrap()
+        .save_to_config()
+        .unwrap();
+        let read_only = AppState::load_read_only(&config_location).unwrap();
+        let github_id = read_only.otp_entries[0].id;
+        let recorded = read_only.record_use(github_id).unwrap();
+        let reloaded = AppState::load_read_only(&config_location).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(recorded.get_entry(github_id).unwrap().use_count, 1);
+        assert_eq!(reloaded.get_entry(github_id).unwrap().use_count, 1);
+    }
+
+    #[test]
+    fn control_requests_while_locked() {
+        let app_state = app_state(&["GitHub"]).lock();
+        let github = EntrySelector::Name {
+            name: "GitHub".to_string(),
+        };
+        assert!(matches!(
+            app_state.handle_control_request(&ControlRequest::Lock),
+            Ok((ControlResponse::Done, Some(UiEvent::Lock)))
+        ));
+        for request in &[
+            ControlRequest::Reload,
+            ControlRequest::List,
+            ControlRequest::Code(github.clone()),
+            ControlRequest::Copy(github),
+        ] {
+            assert!(matches!(
+                app_state.handle_control_request(request),
+                Err(Error::Locked)
+            ));
+        }
+    }
+
+    #[test]
+    fn control_requests_parse_from_json_lines() {
+        let id = Uuid::new_v4();
+        let request = |line: &str| serde_json::from_str::<ControlRequest>(line).unwrap();
+        assert!(matches!(
+            request(r#"{"command": "code", "name": "github"}"#),
+            ControlRequest::Code(EntrySelector::Name { name }) if name == "github"
+        ));
+        assert!(matches!(
+            request(&format!(r#"{{"command": "copy", "id": "{}"}}"#, id)),
+            ControlRequest::Copy(EntrySelector::Id { id: selected }) if selected == id
+        ));
+        assert!(matches!(
+            request(r#"{"command": "list"}"#),
+            ControlRequest::List
+        ));
+        assert!(matches!(
+            request(r#"{"command": "reload"}"#),
+            ControlRequest::Reload
+        ));
+        assert!(matches!(
+            request(r#"{"command": "lock"}"#),
+            ControlRequest::Lock
+        ));
+        assert!(matches!(
+            request(r#"{"command": "setup"}"#),
+            ControlRequest::OpenSetup
+        ));
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "code"}"#).is_err());
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "quit"}"#).is_err());
+    }
+
+    #[test]
+    fn control_replies() {
+        let app_state = app_state(&["GitHub", "GitLab"]);
+        let github_id = app_state.otp_entries[0].id;
+        let reply = |request: &str| {
+            let request = serde_json::from_str(request).unwrap();
+            let (response, event) = app_state.handle_control_request(&request).unwrap();
+            (serde_json::to_value(&response).unwrap(), event)
+        };
+
+        let (list, event) = reply(r#"{"command": "list"}"#);
+        assert!(event.is_none());
+        assert_eq!(list["result"], "entries");
+        assert_eq!(list["entries"][0]["id"], github_id.to_string());
+        assert_eq!(list["entries"][0]["name"], "GitHub");
+        assert_eq!(list["entries"][1]["name"], "GitLab");
+        let list = list.to_string();
+        assert!(!list.contains("secret"));
+        assert!(!list.contains("JBSWY3DPEHPK3PXP"));
+
+        for request in &[
+            r#"{"command": "code", "name": "github"}"#.to_string(),
+            format!(r#"{{"command": "code", "id": "{}"}}"#, github_id),
+        ] {
+            let (code, event) = reply(request);
+            assert!(event.is_none());
+            assert_eq!(code["result"], "code");
+            assert_eq!(code["id"], github_id.to_string());
+            assert_eq!(code["name"], "GitHub");
+            let digits = code["code"].as_str().unwrap();
+            assert_eq!(digits.len(), 6);
+            assert!(digits.chars().all(|c| c.is_ascii_digit()));
+            assert!((1..=30).contains(&code["expires_in"].as_u64().unwrap()));
+        }
+
+        let (copy, event) = reply(r#"{"command": "copy", "name": "github"}"#);
+        assert_eq!(copy, serde_json::json!({"result": "done"}));
+        assert!(matches!(event, Some(UiEvent::CopyEntry(id)) if id == github_id));
+        assert!(matches!(
+            app_state.handle_control_request(&ControlRequest::Code(EntrySelector::Name {
+                name: "bank".to_string()
+            })),
+            Err(Error::NoMatchingEntry(name)) if name == "bank"
+        ));
+        assert_eq!(
+            serde_json::to_value(&ControlResponse::Error {
+                message: "no entry named bank".to_string()
+            })
+            .unwrap(),
+            serde_json::json!({"result": "error", "message": "no entry named bank"})
+        );
+    }
+
+    #[test]
+    fn managed_entry_ids_are_stable() {
+        let path = Path::new("/etc/otptray.d/shared.yaml");
+        assert_eq!(
+            managed_entry_id(path, "GitHub"),
+            managed_entry_id(path, "GitHub")
+        );
+        assert_ne!(
+            managed_entry_id(path, "GitHub"),
+            managed_entry_id(path, "Google")
+        );
+        assert_ne!(
+            managed_entry_id(path, "GitHub"),
+            managed_entry_id(Path::new("/etc/otptray.d/other.yaml"), "GitHub")
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Keeps a single tray running per user. Later invocations, and other
+//! local tools, talk to it over a control socket that only the same
+//! user can connect to. Each request and response is a line of JSON.
+//!
+//! The tray can switch profiles, so there is still one per user, not
+//! one per profile. It records which config file it uses, and requests
+//! meant for another one are refused instead of forwarded.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use crate::common::*;
+
+/// Held by the running tray for as long as it runs. The lock is
+/// released by the OS when the process exits, so a crash never leaves
+/// a stale lock behind.
+pub struct InstanceLock {
+    _lock_file: File,
+}
+
+impl InstanceLock {
+    /// Take the lock, or `None` if another otptray holds it.
+    pub fn acquire() -> Result<Option<Self>, Error> {
+        let dir = instance_dir()?;
+        create_private_dir(&dir)?;
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join("otptray.lock"))?;
+        if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err.into()),
+            };
+        }
+        Ok(Some(InstanceLock {
+            _lock_file: lock_file,
+        }))
+    }
+
+    /// Accept requests on a background thread. `handle` runs on the
+    /// thread of each connection, one request at a time, and what it
+    /// returns, or its error, is sent back.
+    pub fn listen<F>(&self, handle: F) -> Result<(), Error>
+    where
+        F: Fn(&ControlRequest) -> Result<ControlResponse, Error> + Send + 'static,
+    {
+        let listener = bind_socket()?;
+        thread::spawn(move || accept(listener, handle));
+        Ok(())
+    }
+
+    /// Like `listen`, but on this thread, for running without a tray.
+    pub fn serve<F>(&self, handle: F) -> Result<(), Error>
+    where
+        F: Fn(&ControlRequest) -> Result<ControlResponse, Error> + Send + 'static,
+    {
+        accept(bind_socket()?, handle);
+        Ok(())
+    }
+}
+
+fn bind_socket() -> Result<UnixListener, Error> {
+    let socket_path = socket_path()?;
+    // Holding the lock means any socket left here is from a tray that's
+    // gone.
+    match fs::remove_file(&socket_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&socket_path)?;
+    fs::set_permissions(&socket_path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Every connection gets a thread of its own, so a client that stays
+/// connected doesn't keep others waiting.
+fn accept<F>(listener: UnixListener, handle: F)
+where
+    F: Fn(&ControlRequest) -> Result<ControlResponse, Error> + Send + 'static,
+{
+    let handle = Arc::new(Mutex::new(handle));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Failed to accept control connection: {:?}", err);
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &handle) {
+                log::warn!("Failed to handle control connection: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Answer requests until the other end hangs up.
+fn handle_connection<F>(stream: UnixStream, handle: &Mutex<F>) -> Result<(), Error>
+where
+    F: Fn(&ControlRequest) -> Result<ControlResponse, Error>,
+{
+    // The socket's mode already keeps other users out, unless the
+    // directory it's in was made by someone else.
+    let peer_uid = peer_uid(stream.as_raw_fd())?;
+    if peer_uid != unsafe { libc::getuid() } {
+        log::warn!("Refused control connection from uid {}", peer_uid);
+        return Ok(());
+    }
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                log::info!("Got control request: {:?}", request);
+                // A handler that panicked left nothing half done that
+                // later requests could trip over.
+                let handle = handle.lock().unwrap_or_else(PoisonError::into_inner);
+                handle(&request)
+            }
+            Err(err) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid request: {}", err),
+            )
+            .into()),
+        }
+        .unwrap_or_else(|err| ControlResponse::Error {
+            message: err.to_string(),
+        });
+        let mut reply = serde_json::to_string(&response)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        reply.push('\n');
+        (&stream).write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(fd: RawFd) -> io::Result<libc::uid_t> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+#[cfg(target_os = "macos")]
+fn peer_uid(fd: RawFd) -> io::Result<libc::uid_t> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Record which config file the running tray uses. Called by whoever
+/// holds the `InstanceLock`, when it starts and whenever it switches
+/// profiles.
+pub fn record_config(config_location: &ConfigLocation) -> Result<(), Error> {
+    let config_path = absolute(&config_location.config_path()?)?;
+    let record_path = running_config_path()?;
+    // Written to the side and renamed, so it's never read half written.
+    let temp_path = record_path.with_extension("tmp");
+    write_private_file(&temp_path, config_path.as_os_str().as_bytes())?;
+    fs::rename(&temp_path, &record_path)?;
+    Ok(())
+}
+
+/// Fail if the running otptray uses another config file than
+/// `config_location`. Only call this knowing another otptray holds the
+/// `InstanceLock`: the record of one that exited is left behind.
+pub fn check_running_config(config_location: &ConfigLocation) -> Result<(), Error> {
+    let running_config = match fs::read(running_config_path()?) {
+        Ok(contents) => PathBuf::from(OsString::from_vec(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if running_config != absolute(&config_location.config_path()?)? {
+        return Err(Error::OtherConfigRunning(running_config));
+    }
+    Ok(())
+}
+
+/// Send `request` to the running tray, as long as it uses the config
+/// file of `config_location`. Otherwise the request would act on
+/// another profile's entries.
+pub fn forward_request(
+    config_location: &ConfigLocation,
+    request: &ControlRequest,
+) -> Result<ControlResponse, Error> {
+    // Only an otptray that's still running answers on the socket, so
+    // the config it recorded is only checked once it does.
+    let stream = connect()?;
+    check_running_config(config_location)?;
+    send_request(&stream, request)
+}
+
+fn connect() -> Result<UnixStream, Error> {
+    let stream = UnixStream::connect(socket_path()?).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+            io::Error::new(err.kind(), "otptray isn't running")
+        }
+        _ => io::Error::new(
+            err.kind(),
+            format!("couldn't reach the running otptray: {}", err),
+        ),
+    })?;
+    Ok(stream)
+}
+
+/// Send `request` to the running tray. An error response comes back as
+/// an `Err`.
+fn send_request(stream: &UnixStream, request: &ControlRequest) -> Result<ControlResponse, Error> {
+    let mut line =
+        serde_json::to_string(request).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    line.push('\n');
+    (&*stream).write_all(line.as_bytes())?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    match serde_json::from_str(&reply) {
+        Ok(ControlResponse::Error { message }) => {
+            Err(io::Error::new(io::ErrorKind::Other, message).into())
+        }
+        Ok(response) => Ok(response),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("unexpected reply: {}", reply.trim_end()),
+        )
+        .into()),
+    }
+}
+
+/// Per-user directory for the lock and the control socket, in
+/// `$XDG_RUNTIME_DIR` where there is one.
+fn instance_dir() -> Result<PathBuf, Error> {
+    match dirs::runtime_dir() {
+        Some(runtime_dir) => Ok(runtime_dir.join("otptray")),
+        None => ConfigLocation::default().config_dir(),
+    }
+}
+
+fn socket_path() -> Result<PathBuf, Error> {
+    Ok(instance_dir()?.join("control.sock"))
+}
+
+fn running_config_path() -> Result<PathBuf, Error> {
+    Ok(instance_dir()?.join("config-path"))
+}
+
+/// `--config` can be relative to wherever otptray was started.
+fn absolute(path: &Path) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
@@ -0,0 +1,750 @@
+//! otptray on the session bus, as `org.otptray`. The
+//! `org.otptray.Manager` object is for scripts, and answers like the
+//! control socket does. GNOME Shell's search provider and KRunner's
+//! runner show codes where the desktop searches.
+
+use atomic_immut::AtomicImmut;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use uuid::Uuid;
+use zbus::fdo::{DBusProxy, RequestNameFlags, RequestNameReply};
+use zbus::{dbus_interface, fdo, Connection, ObjectServer};
+use zvariant::{ObjectPath, OwnedValue, Value};
+
+use crate::common::*;
+
+const BUS_NAME: &str = "org.otptray";
+const MANAGER_PATH: &str = "/org/otptray/Manager";
+const SEARCH_PROVIDER_PATH: &str = "/org/otptray/SearchProvider";
+const KRUNNER_PATH: &str = "/org/otptray/KRunner";
+/// KRunner's match types, from `Plasma::QueryMatch::Type`.
+const KRUNNER_EXACT_MATCH: i32 = 100;
+const KRUNNER_POSSIBLE_MATCH: i32 = 30;
+/// How long the service waits for a call before checking for signals
+/// to send.
+const SIGNAL_POLL_MS: libc::c_int = 200;
+
+/// Id, name, issuer, group and tags of an entry, as `ListEntries`
+/// returns it. A missing issuer or group is an empty string.
+type EntryRow = (String, String, String, String, Vec<String>);
+
+/// Id, text, icon, type, relevance and properties of a KRunner match.
+type KRunnerMatch = (
+    String,
+    String,
+    String,
+    i32,
+    f64,
+    HashMap<String, OwnedValue>,
+);
+
+#[derive(Clone, Copy, Debug)]
+enum ManagerSignal {
+    EntriesChanged,
+    Locked,
+    Unlocked,
+}
+
+/// Sends the Manager's signals when the state they're about changes.
+pub struct ManagerSignals {
+    tx: mpsc::Sender<ManagerSignal>,
+    locked: bool,
+    entries: Vec<ControlEntry>,
+}
+
+impl ManagerSignals {
+    /// Send the signals for whatever changed since the last call.
+    pub fn update(&mut self, app_state: &AppState) {
+        let entries = app_state
+            .otp_entries
+            .iter()
+            .map(ControlEntry::from)
+            .collect::<Vec<_>>();
+        if app_state.locked != self.locked {
+            self.locked = app_state.locked;
+            let _ = self.tx.send(if self.locked {
+                ManagerSignal::Locked
+            } else {
+                ManagerSignal::Unlocked
+            });
+        }
+        if entries != self.entries {
+            self.entries = entries;
+            let _ = self.tx.send(ManagerSignal::EntriesChanged);
+        }
+    }
+}
+
+/// Publish `org.otptray.Manager`, the GNOME Shell search provider and
+/// the KRunner runner on the session bus. Calls that need the tray are
+/// sent to it as `UiEvent`s on `tx`.
+///
+/// The service runs on its own thread. zbus holds the connection while
+/// it waits for a call, so signals are sent from that thread too.
+pub fn serve(
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+) -> ManagerSignals {
+    serve_on(Connection::new_session, global_app_state, tx)
+}
+
+/// Like `serve`, but on the bus `connect` connects to.
+fn serve_on<C>(
+    connect: C,
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+) -> ManagerSignals
+where
+    C: FnOnce() -> zbus::Result<Connection> + Send + 'static,
+{
+    let (signal_tx, signal_rx) = mpsc::channel();
+    let app_state = global_app_state.load();
+    let entries = app_state
+        .otp_entries
+        .iter()
+        .map(ControlEntry::from)
+        .collect();
+    let locked = app_state.locked;
+    thread::spawn(move || {
+        if let Err(err) =
+            connect().and_then(|connection| run(connection, global_app_state, tx, signal_rx))
+        {
+            log::warn!("Not serving otptray on D-Bus: {:?}", err);
+        }
+    });
+    ManagerSignals {
+        tx: signal_tx,
+        locked,
+        entries,
+    }
+}
+
+fn run(
+    connection: Connection,
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+    signal_rx: mpsc::Receiver<ManagerSignal>,
+) -> zbus::Result<()> {
+    let reply =
+        DBusProxy::new(&connection)?.request_name(BUS_NAME, RequestNameFlags::DoNotQueue.into())?;
+    if reply != RequestNameReply::PrimaryOwner {
+        return Err(fdo::Error::AddressInUse(format!("{} is already taken", BUS_NAME)).into());
+    }
+
+    let manager_path = ObjectPath::try_from(MANAGER_PATH)?;
+    let mut object_server = ObjectServer::new(&connection);
+    object_server.at(
+        &manager_path,
+        Manager {
+            global_app_state: global_app_state.clone(),
+            tx: tx.clone(),
+        },
+    )?;
+    object_server.at(
+        &ObjectPath::try_from(SEARCH_PROVIDER_PATH)?,
+        SearchProvider {
+            global_app_state: global_app_state.clone(),
+            tx: tx.clone(),
+        },
+    )?;
+    object_server.at(
+        &ObjectPath::try_from(KRUNNER_PATH)?,
+        KRunner {
+            global_app_state,
+            tx,
+        },
+    )?;
+
+    let mut poll_fd = libc::pollfd {
+        fd: connection.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        if unsafe { libc::poll(&mut poll_fd, 1, SIGNAL_POLL_MS) } > 0 {
+            if let Err(err) = object_server.try_handle_next() {
+                log::warn!("Failed to handle D-Bus message: {:?}", err);
+                if poll_fd.revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+                    return Err(err);
+                }
+            }
+        }
+        loop {
+            let signal = match signal_rx.try_recv() {
+                Ok(signal) => signal,
+                Err(mpsc::TryRecvError::Empty) => break,
+                // The tray is gone.
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            };
+            object_server.with(&manager_path, |manager: &Manager| match signal {
+                ManagerSignal::EntriesChanged => manager.entries_changed(),
+                ManagerSignal::Locked => manager.locked(),
+                ManagerSignal::Unlocked => manager.unlocked(),
+            })?;
+        }
+    }
+}
+
+struct Manager {
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+}
+
+impl Manager {
+    /// Handle `request` like the control socket does.
+    fn request(&self, request: ControlRequest) -> fdo::Result<ControlResponse> {
+        let (response, event) = self
+            .global_app_state
+            .load()
+            .handle_control_request(&request)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+        if let Some(event) = event {
+            let _ = self.tx.send(event);
+        }
+        Ok(response)
+    }
+}
+
+/// Entries are given by id, or by a name matched like on the command
+/// line.
+fn entry_selector(entry: &str) -> EntrySelector {
+    match Uuid::parse_str(entry) {
+        Ok(id) => EntrySelector::Id { id },
+        Err(_) => EntrySelector::Name {
+            name: entry.to_string(),
+        },
+    }
+}
+
+#[dbus_interface(name = "org.otptray.Manager")]
+impl Manager {
+    fn list_entries(&self) -> fdo::Result<Vec<EntryRow>> {
+        match self.request(ControlRequest::List)? {
+            ControlResponse::Entries { entries } => Ok(entries
+                .into_iter()
+                .map(|entry| {
+                    (
+                        entry.id.to_string(),
+                        entry.name,
+                        entry.issuer.unwrap_or_default(),
+                        entry.group.unwrap_or_default(),
+                        entry.tags,
+                    )
+                })
+                .collect()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// The current code, and the seconds until it changes.
+    fn get_code(&self, entry: &str) -> fdo::Result<(String, u64)> {
+        match self.request(ControlRequest::Code(entry_selector(entry)))? {
+            ControlResponse::Code {
+                code, expires_in, ..
+            } => Ok((code, expires_in)),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    fn copy_code(&self, entry: &str) -> fdo::Result<()> {
+        self.request(ControlRequest::Copy(entry_selector(entry)))
+            .map(|_| ())
+    }
+
+    fn open_setup(&self) -> fdo::Result<()> {
+        self.request(ControlRequest::OpenSetup).map(|_| ())
+    }
+
+    fn lock(&self) -> fdo::Result<()> {
+        self.request(ControlRequest::Lock).map(|_| ())
+    }
+
+    #[dbus_interface(signal)]
+    fn entries_changed(&self) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn locked(&self) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn unlocked(&self) -> zbus::Result<()>;
+}
+
+fn unexpected_response(response: ControlResponse) -> fdo::Error {
+    fdo::Error::Failed(format!("unexpected response: {:?}", response))
+}
+
+/// Shows codes in the GNOME Shell overview. Results are entry ids.
+struct SearchProvider {
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+}
+
+impl SearchProvider {
+    /// Nothing matches while otptray is locked.
+    fn search(&self, terms: &[String]) -> Vec<String> {
+        let app_state = self.global_app_state.load();
+        if app_state.locked {
+            return Vec::new();
+        }
+        let query = terms.join(" ");
+        app_state
+            .otp_entries
+            .iter()
+            .filter(|entry| entry.matches_search(&query))
+            .map(|entry| entry.id.to_string())
+            .collect()
+    }
+}
+
+#[dbus_interface(name = "org.gnome.Shell.SearchProvider2")]
+impl SearchProvider {
+    fn get_initial_result_set(&self, terms: Vec<String>) -> Vec<String> {
+        self.search(&terms)
+    }
+
+    fn get_subsearch_result_set(
+        &self,
+        _previous_results: Vec<String>,
+        terms: Vec<String>,
+    ) -> Vec<String> {
+        self.search(&terms)
+    }
+
+    /// The entry's name, with its current code as the description.
+    fn get_result_metas(&self, identifiers: Vec<String>) -> Vec<HashMap<String, OwnedValue>> {
+        let app_state = self.global_app_state.load();
+        if app_state.locked {
+            return Vec::new();
+        }
+        identifiers
+            .into_iter()
+            .filter_map(|identifier| {
+                let entry = app_state.get_entry(Uuid::parse_str(&identifier).ok()?)?;
+                let mut meta = HashMap::new();
+                meta.insert("id".to_string(), Value::from(identifier).into());
+                meta.insert("name".to_string(), Value::from(entry.name.clone()).into());
+                meta.insert(
+                    "description".to_string(),
+                    Value::from(code_description(entry)).into(),
+                );
+                Some(meta)
+            })
+            .collect()
+    }
+
+    fn activate_result(&self, identifier: &str, _terms: Vec<String>, _timestamp: u32) {
+        if let Ok(id) = Uuid::parse_str(identifier) {
+            let _ = self.tx.send(UiEvent::CopyEntry(id));
+        }
+    }
+
+    fn launch_search(&self, _terms: Vec<String>, _timestamp: u32) {
+        let _ = self.tx.send(UiEvent::OpenSetup);
+    }
+}
+
+/// Shows codes in KDE Plasma's KRunner. Match ids are entry ids.
+struct KRunner {
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    tx: glib::Sender<UiEvent>,
+}
+
+#[dbus_interface(name = "org.kde.krunner1")]
+impl KRunner {
+    /// Entries whose name or tags match `query`. An exact name match
+    /// comes first, then names starting with `query`. Nothing matches
+    /// while otptray is locked.
+    #[dbus_interface(name = "Match")]
+    fn match_query(&self, query: &str) -> Vec<KRunnerMatch> {
+        let app_state = self.global_app_state.load();
+        if app_state.locked {
+            return Vec::new();
+        }
+        let lowercase_query = query.to_lowercase();
+        app_state
+            .otp_entries
+            .iter()
+            .filter(|entry| entry.matches_search(query))
+            .map(|entry| {
+                let name = entry.name.to_lowercase();
+                let (match_type, relevance) = if name == lowercase_query {
+                    (KRUNNER_EXACT_MATCH, 1.0)
+                } else if name.starts_with(&lowercase_query) {
+                    (KRUNNER_POSSIBLE_MATCH, 0.8)
+                } else {
+                    (KRUNNER_POSSIBLE_MATCH, 0.5)
+                };
+                let mut properties = HashMap::new();
+                properties.insert(
+                    "subtext".to_string(),
+                    Value::from(code_description(entry)).into(),
+                );
+                (
+                    entry.id.to_string(),
+                    entry.name.clone(),
+                    "otptray".to_string(),
+                    match_type,
+                    relevance,
+                    properties,
+                )
+            })
+            .collect()
+    }
+
+    /// Running a match copies its code, so there are no other actions.
+    fn actions(&self) -> Vec<(String, String, String)> {
+        Vec::new()
+    }
+
+    fn run(&self, match_id: &str, _action_id: &str) {
+        if let Ok(id) = Uuid::parse_str(match_id) {
+            let _ = self.tx.send(UiEvent::CopyEntry(id));
+        }
+    }
+}
+
+/// The entry's current code, for showing under its name.
+fn code_description(entry: &OtpEntry) -> String {
+    format!(
+        "{} · {}s left",
+        entry.get_otp_value().otp,
+        entry.seconds_remaining()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::tests::app_state;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+    use zbus::MessageType;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A bus of our own, so tests never touch the user's session bus.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> TestBus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("couldn't run dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            TestBus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn connect(&self) -> Connection {
+            Connection::new_for_address(&self.address, true).unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// The service on `bus`, and what it sends the tray.
+    struct TestService {
+        global_app_state: Arc<AtomicImmut<AppState>>,
+        signals: ManagerSignals,
+        context: glib::MainContext,
+        events: mpsc::Receiver<UiEvent>,
+    }
+
+    impl TestService {
+        fn start(bus: &TestBus, app_state: AppState) -> TestService {
+            let context = glib::MainContext::new();
+            assert!(context.acquire());
+            let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+            let (event_tx, events) = mpsc::channel();
+            rx.attach(Some(&context), move |event| {
+                let _ = event_tx.send(event);
+                glib::Continue(true)
+            });
+            let global_app_state = Arc::new(AtomicImmut::new(app_state));
+            let address = bus.address.clone();
+            let signals = serve_on(
+                move || Connection::new_for_address(&address, true),
+                global_app_state.clone(),
+                tx,
+            );
+            let client = bus.connect();
+            let started = Instant::now();
+            while !DBusProxy::new(&client)
+                .unwrap()
+                .name_has_owner(BUS_NAME)
+                .unwrap()
+            {
+                assert!(started.elapsed() < TIMEOUT, "{} never showed up", BUS_NAME);
+                thread::sleep(Duration::from_millis(10));
+            }
+            TestService {
+                global_app_state,
+                signals,
+                context,
+                events,
+            }
+        }
+
+        fn next_event(&self) -> UiEvent {
+            let started = Instant::now();
+            loop {
+                self.context.iteration(false);
+                if let Ok(event) = self.events.try_recv() {
+                    return event;
+                }
+                assert!(started.elapsed() < TIMEOUT, "no event from the service");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        /// Make `app_state` current, like the tray does after an event.
+        fn store(&mut self, app_state: AppState) {
+            self.signals.update(&app_state);
+            self.global_app_state.store(app_state);
+        }
+    }
+
+    /// The names of the signals `interface` sends on `bus`.
+    fn listen_for_signals(bus: &TestBus, interface: &str) -> mpsc::Receiver<String> {
+        let connection = bus.connect();
+        DBusProxy::new(&connection)
+            .unwrap()
+            .add_match(&format!("type='signal',interface='{}'", interface))
+            .unwrap();
+        let interface = interface.to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(message) = connection.receive_message() {
+                let header = match message.header() {
+                    Ok(header) => header,
+                    Err(_) => continue,
+                };
+                if header.message_type() == Ok(MessageType::Signal)
+                    && header.interface() == Ok(Some(interface.as_str()))
+                {
+                    if let Ok(Some(member)) = header.member() {
+                        let _ = tx.send(member.to_string());
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    fn call<B>(
+        client: &Connection,
+        path: &str,
+        iface: &str,
+        method: &str,
+        body: &B,
+    ) -> zbus::Result<zbus::Message>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        client.call_method(Some(BUS_NAME), path, Some(iface), method, body)
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn manager() {
+        let bus = TestBus::start();
+        let app_state = app_state(&["GitHub", "Google"]);
+        let github = app_state.otp_entries[0].clone();
+        let mut service = TestService::start(&bus, app_state);
+        let signals = listen_for_signals(&bus, "org.otptray.Manager");
+        let client = bus.connect();
+        let manager = |method: &str, body: &str| {
+            call(&client, MANAGER_PATH, "org.otptray.Manager", method, &body)
+        };
+
+        let entries = call(
+            &client,
+            MANAGER_PATH,
+            "org.otptray.Manager",
+            "ListEntries",
+            &(),
+        )
+        .unwrap()
+        .body::<Vec<EntryRow>>()
+        .unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|(_, name, ..)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["GitHub", "Google"]
+        );
+        assert_eq!(entries[0].0, github.id.to_string());
+
+        let (code, expires_in) = manager("GetCode", "github")
+            .unwrap()
+            .body::<(String, u64)>()
+            .unwrap();
+        assert_eq!(code, github.get_otp_value().otp);
+        assert!(expires_in <= github.step);
+        assert!(manager("GetCode", &github.id.to_string()).is_ok());
+        assert!(manager("GetCode", "nothing").is_err());
+
+        manager("CopyCode", "github").unwrap();
+        assert!(matches!(service.next_event(), UiEvent::CopyEntry(id) if id == github.id));
+
+        let renamed = OtpEntry {
+            name: "GitHub (work)".to_string(),
+            ..github.clone()
+        };
+        let app_state = service
+            .global_app_state
+            .load()
+            .save_entry(renamed, EntryAction::Edit(github.id))
+            .unwrap();
+        service.store(app_state);
+        assert_eq!(signals.recv_timeout(TIMEOUT).unwrap(), "EntriesChanged");
+
+        call(&client, MANAGER_PATH, "org.otptray.Manager", "Lock", &()).unwrap();
+        assert!(matches!(service.next_event(), UiEvent::Lock));
+        let app_state = service.global_app_state.load().lock();
+        service.store(app_state);
+        assert_eq!(signals.recv_timeout(TIMEOUT).unwrap(), "Locked");
+        assert!(manager("GetCode", "github").is_err());
+    }
+
+    fn string(value: &OwnedValue) -> &str {
+        match &**value {
+            Value::Str(string) => string.as_str(),
+            value => panic!("not a string: {:?}", value),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn search_provider() {
+        let bus = TestBus::start();
+        let app_state = app_state(&["GitHub", "GitLab", "Google"]);
+        let github = app_state.otp_entries[0].clone();
+        let gitlab = app_state.otp_entries[1].clone();
+        let mut service = TestService::start(&bus, app_state.clone());
+        let client = bus.connect();
+        let initial_results = |terms: &[&str]| {
+            call_search_provider(&client, "GetInitialResultSet", &terms.to_vec())
+                .body::<Vec<String>>()
+                .unwrap()
+        };
+
+        let results = initial_results(&["git"]);
+        assert_eq!(results, [github.id.to_string(), gitlab.id.to_string()]);
+        let results =
+            call_search_provider(&client, "GetSubsearchResultSet", &(results, vec!["gith"]))
+                .body::<Vec<String>>()
+                .unwrap();
+        assert_eq!(results, [github.id.to_string()]);
+
+        let metas = call_search_provider(&client, "GetResultMetas", &results)
+            .body::<Vec<HashMap<String, OwnedValue>>>()
+            .unwrap();
+        assert_eq!(metas.len(), 1);
+        assert_eq!(&*metas[0]["name"], &Value::from("GitHub"));
+        assert!(string(&metas[0]["description"]).starts_with(&github.get_otp_value().otp));
+
+        call_search_provider(
+            &client,
+            "ActivateResult",
+            &(github.id.to_string(), vec!["git"], 0u32),
+        );
+        assert!(matches!(service.next_event(), UiEvent::CopyEntry(id) if id == github.id));
+        call_search_provider(&client, "LaunchSearch", &(vec!["git"], 0u32));
+        assert!(matches!(service.next_event(), UiEvent::OpenSetup));
+
+        // Even with entries still around, nothing shows while locked.
+        service.store(AppState {
+            locked: true,
+            ..app_state
+        });
+        assert!(initial_results(&["git"]).is_empty());
+        let metas = call_search_provider(&client, "GetResultMetas", &vec![github.id.to_string()])
+            .body::<Vec<HashMap<String, OwnedValue>>>()
+            .unwrap();
+        assert!(metas.is_empty());
+    }
+
+    fn call_search_provider<B>(client: &Connection, method: &str, body: &B) -> zbus::Message
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        call(
+            client,
+            SEARCH_PROVIDER_PATH,
+            "org.gnome.Shell.SearchProvider2",
+            method,
+            body,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn krunner() {
+        let bus = TestBus::start();
+        let app_state = app_state(&["Git", "GitHub", "Google"]);
+        let git = app_state.otp_entries[0].clone();
+        let github = app_state.otp_entries[1].clone();
+        let mut service = TestService::start(&bus, app_state.clone());
+        let client = bus.connect();
+        let matches = |query: &str| {
+            call(&client, KRUNNER_PATH, "org.kde.krunner1", "Match", &query)
+                .unwrap()
+                .body::<Vec<KRunnerMatch>>()
+                .unwrap()
+        };
+
+        let found = matches("git");
+        assert_eq!(
+            found
+                .iter()
+                .map(|(id, _, _, match_type, ..)| (id.as_str(), *match_type))
+                .collect::<Vec<_>>(),
+            [
+                (git.id.to_string().as_str(), KRUNNER_EXACT_MATCH),
+                (github.id.to_string().as_str(), KRUNNER_POSSIBLE_MATCH),
+            ]
+        );
+        assert!(string(&found[0].5["subtext"]).starts_with(&git.get_otp_value().otp));
+
+        call(
+            &client,
+            KRUNNER_PATH,
+            "org.kde.krunner1",
+            "Run",
+            &(github.id.to_string(), ""),
+        )
+        .unwrap();
+        assert!(matches!(service.next_event(), UiEvent::CopyEntry(id) if id == github.id));
+
+        // Even with entries still around, nothing shows while locked.
+        service.store(AppState {
+            locked: true,
+            ..app_state
+        });
+        assert!(matches("git").is_empty());
+    }
+}
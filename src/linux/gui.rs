@@ -1,12 +1,41 @@
 use atomic_immut::AtomicImmut;
+use gio::prelude::*;
 use gtk::prelude::*;
 use libappindicator::{AppIndicator, AppIndicatorStatus};
 
+use chrono::Local;
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
+use super::{dbus, session};
 use crate::common::*;
+use crate::instance::{self, InstanceLock};
 
-fn otp_entry_window(otp_entry: &OtpEntry, entry_action: EntryAction, tx: glib::Sender<UiEvent>) {
+fn form_field(label: &str, value: &str) -> (gtk::Box, gtk::Entry) {
+    let entry = gtk::EntryBuilder::new()
+        .buffer(&gtk::EntryBuffer::new(Some(value)))
+        .build();
+    let field_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_bottom(10)
+        .build();
+    field_box.add(&gtk::LabelBuilder::new().label(label).build());
+    field_box.add(&entry);
+    (field_box, entry)
+}
+
+fn otp_entry_window(
+    otp_entry: &OtpEntry,
+    entry_action: EntryAction,
+    tx: glib::Sender<UiEvent>,
+) -> gtk::Window {
     let window = gtk::WindowBuilder::new().build();
 
     let page_box = gtk::BoxBuilder::new()
@@ -28,8 +57,13 @@ fn otp_entry_window(otp_entry: &OtpEntry, entry_action: EntryAction, tx: glib::S
     name_box.add(&gtk::LabelBuilder::new().label("Name").build());
     name_box.add(&name_entry);
 
+    let (issuer_box, issuer_entry) =
+        form_field("Issuer", otp_entry.issuer.as_deref().unwrap_or_default());
+    let (account_box, account_entry) =
+        form_field("Account", otp_entry.account.as_deref().unwrap_or_default());
+
     let secret_entry = gtk::EntryBuilder::new()
-        .buffer(&gtk::EntryBuffer::new(Some(&otp_entry.secret_hash)))
+        .buffer(&gtk::EntryBuffer::new(Some(otp_entry.secret_hash.expose())))
         .build();
     let secret_box = gtk::BoxBuilder::new()
         .orientation(gtk::Orientation::Vertical)
@@ -84,11 +118,46 @@ fn otp_entry_window(otp_entry: &OtpEntry, entry_action: EntryAction, tx: glib::S
     );
     digit_box.add(&digit_entry);
 
+    let (tags_box, tags_entry) = form_field("Tags (comma separated)", &otp_entry.tags.join(", "));
+    let (notes_box, notes_entry) =
+        form_field("Notes", otp_entry.notes.as_deref().unwrap_or_default());
+
     form_box.add(&name_box);
+    form_box.add(&issuer_box);
+    form_box.add(&account_box);
     form_box.add(&secret_box);
     form_box.add(&hash_fn_box);
     form_box.add(&step_box);
     form_box.add(&digit_box);
+    form_box.add(&tags_box);
+    form_box.add(&notes_box);
+
+    let timestamps = vec![
+        otp_entry.created_at.map(|created_at| {
+            format!(
+                "Created {}",
+                created_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+            )
+        }),
+        otp_entry.last_used_at.map(|last_used_at| {
+            format!(
+                "Last used {}",
+                last_used_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+            )
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if !timestamps.is_empty() {
+        let timestamp_label = gtk::LabelBuilder::new()
+            .label(&timestamps.join(" · "))
+            .margin_start(5)
+            .margin_end(5)
+            .build();
+        timestamp_label.get_style_context().add_class("dim-label");
+        form_box.add(&timestamp_label);
+    }
 
     let form_frame = gtk::FrameBuilder::new()
         .label(entry_action.window_title())
@@ -115,13 +184,21 @@ fn otp_entry_window(otp_entry: &OtpEntry, entry_action: EntryAction, tx: glib::S
         let new_otp_entry = OtpEntry::input_validate(
             name_entry.get_buffer().get_text(),
             step_entry.get_buffer().get_text(),
-            secret_entry.get_buffer().get_text(),
+            &Zeroizing::new(secret_entry.get_buffer().get_text()),
             hash_fn_combo.get_active_id().unwrap().as_str().to_string(), // Our combo box should always have a value
             digit_entry.get_buffer().get_text(),
-        );
+        )
+        .map(|entry| {
+            entry.with_details(
+                issuer_entry.get_buffer().get_text(),
+                account_entry.get_buffer().get_text(),
+                tags_entry.get_buffer().get_text(),
+                notes_entry.get_buffer().get_text(),
+            )
+        });
         match new_otp_entry {
             Ok(entry) => {
-                let _ = tx.send(UiEvent::SaveEntry(entry, entry_action));
+                let _ = tx.send(UiEvent::SaveEntry(Box::new(entry), entry_action));
             }
             Err(err) => log::info!("Invalid entry input: {:?}", err), // TODO: Pop up some error window
         }
@@ -151,52 +228,497 @@ fn otp_entry_window(otp_entry: &OtpEntry, entry_action: EntryAction, tx: glib::S
         Inhibit(false)
     });
     window.add(&page_box);
-    window.set_default_size(350, 350);
+    window.set_default_size(350, 550);
     window.set_title(entry_action.window_title());
     window.set_position(gtk::WindowPosition::Center);
     window.show_all();
+    window
+}
+
+fn group_window(
+    groups: &[String],
+    current_group: Option<&str>,
+    group_action: GroupAction,
+    tx: glib::Sender<UiEvent>,
+) {
+    let window = gtk::WindowBuilder::new().build();
+
+    let page_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let form_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+
+    // An empty id stands for "No group", which no group can be named.
+    let group_combo = gtk::ComboBoxTextBuilder::new().margin(5).build();
+    if let GroupAction::MoveEntries(_) = group_action {
+        group_combo.append(Some(""), "No group");
+    }
+    for group in groups {
+        group_combo.append(Some(group), group);
+    }
+    if !group_combo.set_active_id(current_group.or(Some(""))) {
+        group_combo.set_active(Some(0));
+    }
+    let (name_box, name_entry) = form_field("Name", "");
+
+    match group_action {
+        GroupAction::Create => form_box.add(&name_box),
+        GroupAction::Rename => {
+            form_box.add(&group_combo);
+            form_box.add(&name_box);
+        }
+        GroupAction::Delete | GroupAction::MoveEntries(_) => form_box.add(&group_combo),
+    }
+
+    let form_frame = gtk::FrameBuilder::new()
+        .label(group_action.window_title())
+        .child(&form_box)
+        .vexpand(true)
+        .margin(5)
+        .build();
+
+    let button_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let save_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Save")
+        .sensitive(
+            matches!(group_action, GroupAction::Create) || group_combo.get_active_id().is_some(),
+        )
+        .build();
+    let cancel_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Cancel")
+        .build();
+
+    let save_window = window.clone();
+    let save_action = group_action.clone();
+    save_button.connect_clicked(move |_| {
+        let selected_group = group_combo
+            .get_active_id()
+            .map(|id| id.as_str().to_string())
+            .filter(|id| !id.is_empty());
+        let name = name_entry.get_buffer().get_text();
+        let event = match (&save_action, selected_group) {
+            (GroupAction::Create, _) => Some(UiEvent::CreateGroup(name)),
+            (GroupAction::Rename, Some(group)) => Some(UiEvent::RenameGroup(group, name)),
+            (GroupAction::Delete, Some(group)) => Some(UiEvent::DeleteGroup(group)),
+            (GroupAction::MoveEntries(entry_ids), group) => {
+                Some(UiEvent::MoveToGroup(entry_ids.clone(), group))
+            }
+            _ => None,
+        };
+        if let Some(event) = event {
+            let _ = tx.send(event);
+        }
+        save_window.close();
+    });
+    let cancel_window = window.clone();
+    cancel_button.connect_clicked(move |_| {
+        cancel_window.close();
+    });
+    button_box.add(&save_button);
+    button_box.add(&cancel_button);
+
+    page_box.add(&form_frame);
+    page_box.add(&button_box);
+
+    window.connect_key_press_event(move |_, key_event| {
+        match key_event.get_keyval().name() {
+            Some(key_name) if key_name == "Return" => {
+                save_button.clicked();
+            }
+            Some(key_name) if key_name == "Escape" => {
+                cancel_button.clicked();
+            }
+            _ => {}
+        }
+
+        Inhibit(false)
+    });
+    window.add(&page_box);
+    window.set_default_size(300, 150);
+    window.set_title(group_action.window_title());
+    window.set_position(gtk::WindowPosition::Center);
+    window.show_all();
+}
+
+/// Change the tags, algorithm, step or digits of several entries at
+/// once. Blank fields are left alone.
+fn bulk_edit_window(entry_ids: Vec<Uuid>, tx: glib::Sender<UiEvent>) {
+    let window = gtk::WindowBuilder::new().build();
+
+    let page_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let form_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+
+    let (tags_box, tags_entry) = form_field("Add tags (comma separated)", "");
+    // An empty id stands for keeping each entry's own hash function.
+    let hash_fn_combo = gtk::ComboBoxTextBuilder::new().build();
+    hash_fn_combo.append(Some(""), "Unchanged");
+    hash_fn_combo.append(Some("sha1"), "sha1");
+    hash_fn_combo.append(Some("sha256"), "sha256");
+    hash_fn_combo.append(Some("sha512"), "sha512");
+    hash_fn_combo.set_active_id(Some(""));
+    let hash_fn_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_bottom(10)
+        .build();
+    hash_fn_box.add(&gtk::LabelBuilder::new().label("Hash Function").build());
+    hash_fn_box.add(&hash_fn_combo);
+    let (step_box, step_entry) = form_field("Step", "");
+    let (digit_count_box, digit_count_entry) = form_field("Digit Count", "");
+
+    form_box.add(&tags_box);
+    form_box.add(&hash_fn_box);
+    form_box.add(&step_box);
+    form_box.add(&digit_count_box);
+
+    let form_frame = gtk::FrameBuilder::new()
+        .label(&format!("Edit {} entries", entry_ids.len()))
+        .child(&form_box)
+        .vexpand(true)
+        .margin(5)
+        .build();
+
+    let button_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let save_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Save")
+        .build();
+    let cancel_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Cancel")
+        .build();
+
+    let save_window = window.clone();
+    save_button.connect_clicked(move |_| {
+        let hash_fn = hash_fn_combo
+            .get_active_id()
+            .map(|id| id.as_str().to_string())
+            .filter(|id| !id.is_empty());
+        match BulkEdit::input_validate(
+            tags_entry.get_buffer().get_text(),
+            hash_fn,
+            step_entry.get_buffer().get_text(),
+            digit_count_entry.get_buffer().get_text(),
+        ) {
+            Ok(bulk_edit) => {
+                let _ = tx.send(UiEvent::BulkEditEntries(entry_ids.clone(), bulk_edit));
+                save_window.close();
+            }
+            Err(err) => log::error!("Failed to validate bulk edit: {:?}", err),
+        }
+    });
+    let cancel_window = window.clone();
+    cancel_button.connect_clicked(move |_| {
+        cancel_window.close();
+    });
+    button_box.add(&save_button);
+    button_box.add(&cancel_button);
+
+    page_box.add(&form_frame);
+    page_box.add(&button_box);
+
+    window.connect_key_press_event(move |_, key_event| {
+        match key_event.get_keyval().name() {
+            Some(key_name) if key_name == "Return" => {
+                save_button.clicked();
+            }
+            Some(key_name) if key_name == "Escape" => {
+                cancel_button.clicked();
+            }
+            _ => {}
+        }
+
+        Inhibit(false)
+    });
+    window.add(&page_box);
+    window.set_default_size(300, 300);
+    window.set_title("Edit Entries");
+    window.set_position(gtk::WindowPosition::Center);
+    window.show_all();
+}
+
+/// Ask where to export the selected entries to.
+fn export_dialog(parent: Option<&gtk::Window>, entry_ids: Vec<Uuid>, tx: glib::Sender<UiEvent>) {
+    let dialog = gtk::FileChooserDialog::with_buttons(
+        Some("Export Entries"),
+        parent,
+        gtk::FileChooserAction::Save,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Export", gtk::ResponseType::Accept),
+        ],
+    );
+    dialog.set_current_name("otptray-export.yaml");
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(path) = dialog.get_filename() {
+                let _ = tx.send(UiEvent::ExportEntries(entry_ids.clone(), path));
+            }
+        }
+        dialog.close();
+    });
+    dialog.show_all();
+}
+
+/// Drag and drop target for reordering setup list rows. The data is
+/// the dragged entry's id.
+const ENTRY_DRAG_TARGET: &str = "application/x-otptray-entry";
+
+fn entry_drag_targets() -> Vec<gtk::TargetEntry> {
+    vec![gtk::TargetEntry::new(
+        ENTRY_DRAG_TARGET,
+        gtk::TargetFlags::SAME_APP,
+        0,
+    )]
 }
 
 fn build_otp_list(otp_list: &mut gtk::ListBox, otp_entries: &[OtpEntry]) {
+    // Keep the same entries selected across rebuilds, such as after
+    // they were moved.
+    let previously_selected = selected_entry_ids(otp_list);
     otp_list.foreach(|c| otp_list.remove(c));
 
-    for (i, entry) in otp_entries.iter().enumerate() {
+    for entry in otp_entries {
+        let row_box = gtk::BoxBuilder::new()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let name = if entry.pinned {
+            format!("★ {}", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        row_box.add(&gtk::LabelBuilder::new().label(&name).build());
+        let details = entry.formatted_details();
+        if !details.is_empty() {
+            let details_label = gtk::LabelBuilder::new().label(&details).build();
+            details_label.get_style_context().add_class("dim-label");
+            row_box.add(&details_label);
+        }
+        let source = match &entry.group {
+            Some(group) => format!("{} · {}", group, entry.source.formatted_display()),
+            None => entry.source.formatted_display(),
+        };
+        let source_label = gtk::LabelBuilder::new().label(&source).build();
+        source_label.get_style_context().add_class("dim-label");
+        row_box.add(&source_label);
+
+        // Rows have no window of their own to start a drag from, so
+        // the drag source is an event box around the row's contents.
+        let drag_box = gtk::EventBoxBuilder::new().child(&row_box).build();
+        if !entry.is_managed() {
+            drag_box.drag_source_set(
+                gdk::ModifierType::BUTTON1_MASK,
+                &entry_drag_targets(),
+                gdk::DragAction::MOVE,
+            );
+            let entry_id = entry.id.to_string();
+            drag_box.connect_drag_data_get(move |_, _, selection_data, _, _| {
+                selection_data.set(
+                    &gdk::Atom::intern(ENTRY_DRAG_TARGET),
+                    8,
+                    entry_id.as_bytes(),
+                );
+            });
+        }
+
+        // Managed entries can't be selected, so they can't be edited,
+        // removed or moved either.
         let row = gtk::ListBoxRowBuilder::new()
-            .child(&gtk::LabelBuilder::new().label(&entry.name).build())
+            .child(&drag_box)
+            .selectable(!entry.is_managed())
+            .name(&entry.id.to_string())
             .build();
         otp_list.add(&row);
-        if i == 0 {
-            otp_list.select_row(Some(&row));
-        }
     }
+    restore_selection(otp_list, &previously_selected);
 
     otp_list.show_all();
 }
 
-fn otp_configuration(otp_entries: &[OtpEntry]) -> (gtk::Frame, gtk::ListBox) {
+/// Select the rows for `preferred_ids` that aren't filtered out, or
+/// else the first selectable row that is.
+fn restore_selection(otp_list: &gtk::ListBox, preferred_ids: &[Uuid]) {
+    let rows = otp_list
+        .get_children()
+        .into_iter()
+        .filter_map(|child| child.downcast::<gtk::ListBoxRow>().ok())
+        .filter(|row| row.get_selectable() && row.get_child_visible())
+        .collect::<Vec<_>>();
+    otp_list.unselect_all();
+    let mut preferred_rows = rows
+        .iter()
+        .filter(|row| {
+            row_entry_id(row)
+                .map(|entry_id| preferred_ids.contains(&entry_id))
+                .unwrap_or(false)
+        })
+        .peekable();
+    if preferred_rows.peek().is_none() {
+        otp_list.select_row(rows.first());
+    }
+    for row in preferred_rows {
+        otp_list.select_row(Some(row));
+    }
+}
+
+/// The ids of the entries behind the selected setup list rows that
+/// the search hasn't hidden, in list order.
+fn selected_entry_ids(otp_list: &gtk::ListBox) -> Vec<Uuid> {
+    otp_list
+        .get_selected_rows()
+        .iter()
+        .filter(|row| row.get_child_visible())
+        .filter_map(row_entry_id)
+        .collect()
+}
+
+/// The first selected entry, for actions that work on one entry.
+fn selected_entry_id(otp_list: &gtk::ListBox) -> Option<Uuid> {
+    selected_entry_ids(otp_list).first().copied()
+}
+
+/// Rows are named after their entry's id in `build_otp_list`.
+fn row_entry_id(row: &gtk::ListBoxRow) -> Option<Uuid> {
+    Uuid::parse_str(row.get_widget_name().as_str()).ok()
+}
+
+/// The setup page's entry list, along with what its search filters
+/// on. Only the searched fields are kept, not the entries and their
+/// secrets.
+struct SearchableOtpList {
+    otp_list: gtk::ListBox,
+    search_entry: gtk::SearchEntry,
+    otp_entries: Rc<RefCell<Vec<SearchableEntry>>>,
+}
+
+impl SearchableOtpList {
+    fn rebuild(&mut self, otp_entries: &[OtpEntry]) {
+        *self.otp_entries.borrow_mut() = otp_entries.iter().map(SearchableEntry::from).collect();
+        build_otp_list(&mut self.otp_list, otp_entries);
+    }
+}
+
+fn otp_configuration(
+    otp_entries: &[OtpEntry],
+    tx: glib::Sender<UiEvent>,
+) -> (gtk::Frame, SearchableOtpList) {
     let mut otp_list = gtk::ListBoxBuilder::new()
-        .selection_mode(gtk::SelectionMode::Single)
+        .selection_mode(gtk::SelectionMode::Multiple)
+        .build();
+    let search_entry = gtk::SearchEntryBuilder::new()
+        .placeholder_text("Search name, issuer, tags or notes")
+        .margin(5)
         .build();
+    let searched_entries = Rc::new(RefCell::new(
+        otp_entries
+            .iter()
+            .map(SearchableEntry::from)
+            .collect::<Vec<_>>(),
+    ));
+
+    let filter_search_entry = search_entry.clone();
+    let filter_entries = searched_entries.clone();
+    otp_list.set_filter_func(Some(Box::new(move |row| {
+        let query = filter_search_entry.get_text();
+        let entries = filter_entries.borrow();
+        row_entry_id(row)
+            .and_then(|entry_id| entries.iter().find(|entry| entry.id == entry_id))
+            .map(|entry| entry.matches(&query))
+            .unwrap_or(true)
+    })));
+    let search_otp_list = otp_list.clone();
+    search_entry.connect_search_changed(move |_| {
+        search_otp_list.invalidate_filter();
+        // Edit and Remove act on the selection, so never leave it on
+        // a row that was just hidden.
+        restore_selection(&search_otp_list, &selected_entry_ids(&search_otp_list));
+    });
     build_otp_list(&mut otp_list, otp_entries);
+
+    // Dropping a row onto another moves it to that row's place.
+    otp_list.drag_dest_set(
+        gtk::DestDefaults::ALL,
+        &entry_drag_targets(),
+        gdk::DragAction::MOVE,
+    );
+    let drop_tx = tx.clone();
+    otp_list.connect_drag_data_received(move |otp_list, _, _, y, selection_data, _, _| {
+        let dragged_id = String::from_utf8(selection_data.get_data())
+            .ok()
+            .and_then(|id| Uuid::parse_str(&id).ok());
+        let target_id = otp_list.get_row_at_y(y).and_then(|row| row_entry_id(&row));
+        if let (Some(dragged_id), Some(target_id)) = (dragged_id, target_id) {
+            let _ = drop_tx.send(UiEvent::MoveEntryTo(dragged_id, target_id));
+        }
+    });
+
+    // Alt+Up and Alt+Down move the selected entry.
+    otp_list.connect_key_press_event(move |otp_list, key_event| {
+        if !key_event.get_state().contains(gdk::ModifierType::MOD1_MASK) {
+            return Inhibit(false);
+        }
+        let offset = match key_event.get_keyval().name() {
+            Some(key_name) if key_name == "Up" => -1,
+            Some(key_name) if key_name == "Down" => 1,
+            _ => return Inhibit(false),
+        };
+        if let Some(entry_id) = selected_entry_id(otp_list) {
+            let _ = tx.send(UiEvent::MoveEntryBy(entry_id, offset));
+        }
+        Inhibit(true)
+    });
+
     let viewport = gtk::ViewportBuilder::new().child(&otp_list).build();
     let window = gtk::ScrolledWindowBuilder::new()
         .hexpand(true)
         .vexpand(true)
         .child(&viewport)
         .build();
+    let list_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    list_box.add(&search_entry);
+    list_box.add(&window);
     let frame = gtk::FrameBuilder::new()
         .label("One-Time Password Setup")
         .margin(5)
-        .child(&window)
+        .child(&list_box)
         .build();
-    (frame, otp_list)
+    (
+        frame,
+        SearchableOtpList {
+            otp_list,
+            search_entry,
+            otp_entries: searched_entries,
+        },
+    )
 }
 
-fn setup_page(app_state: &AppState, tx: glib::Sender<UiEvent>) -> (gtk::Box, gtk::ListBox) {
+fn setup_page(
+    app_state: &AppState,
+    tx: glib::Sender<UiEvent>,
+) -> (gtk::Box, SearchableOtpList, gtk::ComboBoxText) {
     let page_box = gtk::BoxBuilder::new()
         .orientation(gtk::Orientation::Vertical)
         .build();
-    let (frame, otp_list) = otp_configuration(&app_state.otp_entries);
+    let (frame, searchable_list) = otp_configuration(&app_state.otp_entries, tx.clone());
+    let otp_list = &searchable_list.otp_list;
     let button_box = gtk::BoxBuilder::new()
         .orientation(gtk::Orientation::Horizontal)
         .margin(5)
@@ -215,11 +737,8 @@ fn setup_page(app_state: &AppState, tx: glib::Sender<UiEvent>) -> (gtk::Box, gtk
     let edit_otp_list = otp_list.clone();
     let edit_tx = tx.clone();
     edit_button.connect_clicked(move |_| {
-        if let Some(selected_row) = edit_otp_list
-            .get_selected_row()
-            .map(|row| row.get_index() as usize)
-        {
-            let _ = edit_tx.send(UiEvent::OpenEntry(EntryAction::Edit(selected_row)));
+        if let Some(entry_id) = selected_entry_id(&edit_otp_list) {
+            let _ = edit_tx.send(UiEvent::OpenEntry(EntryAction::Edit(entry_id)));
         }
     });
     let remove_button = gtk::ButtonBuilder::new()
@@ -229,37 +748,454 @@ fn setup_page(app_state: &AppState, tx: glib::Sender<UiEvent>) -> (gtk::Box, gtk
     let delete_otp_list = otp_list.clone();
     let remove_tx = tx.clone();
     remove_button.connect_clicked(move |_| {
-        if let Some(selected_row) = delete_otp_list
-            .get_selected_row()
-            .map(|row| row.get_index() as usize)
-        {
-            let _ = remove_tx.send(UiEvent::RemoveEntry(selected_row));
+        let entry_ids = selected_entry_ids(&delete_otp_list);
+        if !entry_ids.is_empty() {
+            let _ = remove_tx.send(UiEvent::ConfirmRemoveEntries(entry_ids));
+        }
+    });
+    let pin_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Pin / Unpin")
+        .build();
+    let pin_otp_list = otp_list.clone();
+    let pin_tx = tx.clone();
+    pin_button.connect_clicked(move |_| {
+        if let Some(entry_id) = selected_entry_id(&pin_otp_list) {
+            let _ = pin_tx.send(UiEvent::TogglePinned(entry_id));
         }
     });
     button_box.add(&add_button);
     button_box.add(&edit_button);
     button_box.add(&remove_button);
+    button_box.add(&pin_button);
+    for (label, offset) in &[("Move Up", -1), ("Move Down", 1)] {
+        let move_button = gtk::ButtonBuilder::new().margin_end(3).label(label).build();
+        let move_otp_list = otp_list.clone();
+        let move_tx = tx.clone();
+        let offset = *offset;
+        move_button.connect_clicked(move |_| {
+            if let Some(entry_id) = selected_entry_id(&move_otp_list) {
+                let _ = move_tx.send(UiEvent::MoveEntryBy(entry_id, offset));
+            }
+        });
+        button_box.add(&move_button);
+    }
+
+    let group_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    for (label, group_action) in &[
+        ("New group…", GroupAction::Create),
+        ("Rename group…", GroupAction::Rename),
+        ("Delete group…", GroupAction::Delete),
+    ] {
+        let group_button = gtk::ButtonBuilder::new().margin_end(3).label(label).build();
+        let group_tx = tx.clone();
+        let group_action = group_action.clone();
+        group_button.connect_clicked(move |_| {
+            let _ = group_tx.send(UiEvent::OpenGroup(group_action.clone()));
+        });
+        group_box.add(&group_button);
+    }
+    let move_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Move to group…")
+        .build();
+    let move_otp_list = otp_list.clone();
+    let move_tx = tx.clone();
+    move_button.connect_clicked(move |_| {
+        let entry_ids = selected_entry_ids(&move_otp_list);
+        if !entry_ids.is_empty() {
+            let _ = move_tx.send(UiEvent::OpenGroup(GroupAction::MoveEntries(entry_ids)));
+        }
+    });
+    group_box.add(&move_button);
+
+    let group_order_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let group_order_combo = gtk::ComboBoxTextBuilder::new().margin_end(3).build();
+    build_group_combo(&group_order_combo, &app_state.all_groups());
+    group_order_box.add(
+        &gtk::LabelBuilder::new()
+            .label("Group order")
+            .margin_end(5)
+            .build(),
+    );
+    group_order_box.add(&group_order_combo);
+    for (label, offset) in &[("Move Up", -1), ("Move Down", 1)] {
+        let move_button = gtk::ButtonBuilder::new().margin_end(3).label(label).build();
+        let move_group_combo = group_order_combo.clone();
+        let move_tx = tx.clone();
+        let offset = *offset;
+        move_button.connect_clicked(move |_| {
+            if let Some(group) = move_group_combo.get_active_id() {
+                let _ = move_tx.send(UiEvent::MoveGroupBy(group.as_str().to_string(), offset));
+            }
+        });
+        group_order_box.add(&move_button);
+    }
+
+    let selection_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let bulk_edit_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Edit selected…")
+        .build();
+    let bulk_edit_otp_list = otp_list.clone();
+    let bulk_edit_tx = tx.clone();
+    bulk_edit_button.connect_clicked(move |_| {
+        let entry_ids = selected_entry_ids(&bulk_edit_otp_list);
+        if !entry_ids.is_empty() {
+            let _ = bulk_edit_tx.send(UiEvent::OpenBulkEdit(entry_ids));
+        }
+    });
+    let export_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Export…")
+        .build();
+    let export_otp_list = otp_list.clone();
+    let export_tx = tx.clone();
+    export_button.connect_clicked(move |_| {
+        let entry_ids = selected_entry_ids(&export_otp_list);
+        if !entry_ids.is_empty() {
+            let parent = export_otp_list
+                .get_toplevel()
+                .and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok());
+            export_dialog(parent.as_ref(), entry_ids, export_tx.clone());
+        }
+    });
+    selection_box.add(&bulk_edit_button);
+    selection_box.add(&export_button);
+
+    let order_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let order_combo = gtk::ComboBoxTextBuilder::new().build();
+    for menu_order in &MenuOrder::ALL {
+        order_combo.append(Some(menu_order.id()), menu_order.formatted_display());
+    }
+    order_combo.set_active_id(Some(app_state.menu_order.id()));
+    let order_tx = tx.clone();
+    order_combo.connect_changed(move |combo| {
+        if let Some(menu_order) = combo
+            .get_active_id()
+            .and_then(|id| MenuOrder::from_id(id.as_str()))
+        {
+            let _ = order_tx.send(UiEvent::SetMenuOrder(menu_order));
+        }
+    });
+    order_box.add(
+        &gtk::LabelBuilder::new()
+            .label("Sort tray menu by")
+            .margin_end(5)
+            .build(),
+    );
+    order_box.add(&order_combo);
+
     page_box.add(&frame);
     page_box.add(&button_box);
-    (page_box, otp_list)
+    page_box.add(&group_box);
+    page_box.add(&group_order_box);
+    page_box.add(&selection_box);
+    page_box.add(&order_box);
+    (page_box, searchable_list, group_order_combo)
+}
+
+/// Fill `combo` with `groups`, keeping the selected group selected.
+fn build_group_combo(combo: &gtk::ComboBoxText, groups: &[String]) {
+    let active_id = combo.get_active_id();
+    combo.remove_all();
+    for group in groups {
+        combo.append(Some(group), group);
+    }
+    if !combo.set_active_id(active_id.as_ref().map(|id| id.as_str())) {
+        combo.set_active(Some(0));
+    }
 }
 
-fn about_page() -> gtk::Box {
+fn restore_backup_window(config_location: &ConfigLocation, tx: glib::Sender<UiEvent>) {
+    let window = gtk::WindowBuilder::new().build();
+
+    let page_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let backup_combo = gtk::ComboBoxTextBuilder::new().margin(5).build();
+    match config_location.list_backups() {
+        Ok(backups) => {
+            for backup in &backups {
+                backup_combo.append(backup.path.to_str(), &backup.formatted_display());
+            }
+            if let Some(newest) = backups.first() {
+                backup_combo.set_active_id(newest.path.to_str());
+            }
+        }
+        Err(err) => log::error!("Failed to list configuration backups: {:?}", err),
+    }
+    let form_frame = gtk::FrameBuilder::new()
+        .label("Restore Backup")
+        .child(&backup_combo)
+        .vexpand(true)
+        .margin(5)
+        .build();
+
+    let button_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let restore_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Restore")
+        .sensitive(backup_combo.get_active_id().is_some())
+        .build();
+    let cancel_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Cancel")
+        .build();
+
+    let restore_window = window.clone();
+    restore_button.connect_clicked(move |_| {
+        if let Some(backup_path) = backup_combo.get_active_id() {
+            let _ = tx.send(UiEvent::RestoreBackup(PathBuf::from(backup_path.as_str())));
+        }
+        restore_window.close();
+    });
+    let cancel_window = window.clone();
+    cancel_button.connect_clicked(move |_| {
+        cancel_window.close();
+    });
+    button_box.add(&restore_button);
+    button_box.add(&cancel_button);
+
+    page_box.add(&form_frame);
+    page_box.add(&button_box);
+
+    window.add(&page_box);
+    window.set_default_size(300, 150);
+    window.set_title("Restore Backup");
+    window.set_position(gtk::WindowPosition::Center);
+    window.show_all();
+}
+
+fn about_page(config_location: ConfigLocation, tx: glib::Sender<UiEvent>) -> gtk::Box {
+    let page_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
     let gtk_box = gtk::BoxBuilder::new()
         .orientation(gtk::Orientation::Horizontal)
         .halign(gtk::Align::Center)
+        .vexpand(true)
         .build();
     let label = gtk::LabelBuilder::new().label("About OTPTray").build();
     gtk_box.add(&label);
-    gtk_box
+
+    let maintenance_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let restore_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Restore backup…")
+        .build();
+    restore_button.connect_clicked(move |_| {
+        restore_backup_window(&config_location, tx.clone());
+    });
+    maintenance_box.add(&restore_button);
+    let maintenance_frame = gtk::FrameBuilder::new()
+        .label("Maintenance")
+        .child(&maintenance_box)
+        .margin(5)
+        .build();
+
+    page_box.add(&gtk_box);
+    page_box.add(&maintenance_frame);
+    page_box
+}
+
+fn build_trash_list(trash_list: &mut gtk::ListBox, app_state: &AppState) {
+    trash_list.foreach(|c| trash_list.remove(c));
+
+    let retention = app_state.trash_retention();
+    for trashed in app_state.trash.iter().rev() {
+        let row_box = gtk::BoxBuilder::new()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        row_box.add(&gtk::LabelBuilder::new().label(&trashed.entry.name).build());
+        let removed_label = gtk::LabelBuilder::new()
+            .label(&trashed.formatted_display(retention))
+            .build();
+        removed_label.get_style_context().add_class("dim-label");
+        row_box.add(&removed_label);
+        let row = gtk::ListBoxRowBuilder::new()
+            .child(&row_box)
+            .name(&trashed.entry.id.to_string())
+            .build();
+        trash_list.add(&row);
+    }
+    if let Some(row) = trash_list.get_row_at_index(0) {
+        trash_list.select_row(Some(&row));
+    }
+
+    trash_list.show_all();
+}
+
+fn trash_page(app_state: &AppState, tx: glib::Sender<UiEvent>) -> (gtk::Box, gtk::ListBox) {
+    let page_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let mut trash_list = gtk::ListBoxBuilder::new()
+        .selection_mode(gtk::SelectionMode::Single)
+        .build();
+    build_trash_list(&mut trash_list, app_state);
+    let viewport = gtk::ViewportBuilder::new().child(&trash_list).build();
+    let window = gtk::ScrolledWindowBuilder::new()
+        .hexpand(true)
+        .vexpand(true)
+        .child(&viewport)
+        .build();
+    let frame = gtk::FrameBuilder::new()
+        .label("Removed Entries")
+        .margin(5)
+        .child(&window)
+        .build();
+
+    let button_box = gtk::BoxBuilder::new()
+        .orientation(gtk::Orientation::Horizontal)
+        .margin(5)
+        .build();
+    let restore_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Restore")
+        .build();
+    let restore_trash_list = trash_list.clone();
+    let restore_tx = tx.clone();
+    restore_button.connect_clicked(move |_| {
+        if let Some(entry_id) = selected_entry_id(&restore_trash_list) {
+            let _ = restore_tx.send(UiEvent::RestoreEntry(entry_id));
+        }
+    });
+    let purge_button = gtk::ButtonBuilder::new()
+        .margin_end(3)
+        .label("Delete Permanently")
+        .build();
+    let purge_trash_list = trash_list.clone();
+    purge_button.connect_clicked(move |_| {
+        if let Some(entry_id) = selected_entry_id(&purge_trash_list) {
+            let _ = tx.send(UiEvent::ConfirmPurgeEntry(entry_id));
+        }
+    });
+    button_box.add(&restore_button);
+    button_box.add(&purge_button);
+
+    page_box.add(&frame);
+    page_box.add(&button_box);
+    (page_box, trash_list)
+}
+
+/// The parts of the open setup window that follow changes to the
+/// app state.
+struct SetupWidgets {
+    otp_list: SearchableOtpList,
+    group_order_combo: gtk::ComboBoxText,
+    trash_list: gtk::ListBox,
+    undo_bar: gtk::InfoBar,
+    undo_label: gtk::Label,
 }
 
-fn setup_window(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> gtk::ListBox {
+impl SetupWidgets {
+    fn refresh(&mut self, app_state: &AppState) {
+        self.otp_list.rebuild(&app_state.otp_entries);
+        build_group_combo(&self.group_order_combo, &app_state.all_groups());
+        build_trash_list(&mut self.trash_list, app_state);
+    }
+
+    fn show_undo(&self, removed: &str) {
+        self.undo_label
+            .set_text(&format!("Moved {} to the trash", removed));
+        self.undo_bar.show();
+    }
+
+    fn window(&self) -> Option<gtk::Window> {
+        self.otp_list
+            .otp_list
+            .get_toplevel()
+            .and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok())
+    }
+}
+
+fn undo_bar(tx: glib::Sender<UiEvent>) -> (gtk::InfoBar, gtk::Label) {
+    let undo_bar = gtk::InfoBarBuilder::new()
+        .message_type(gtk::MessageType::Info)
+        .show_close_button(true)
+        .build();
+    let undo_label = gtk::LabelBuilder::new().build();
+    undo_bar.get_content_area().add(&undo_label);
+    undo_bar.add_button("Undo", gtk::ResponseType::Accept);
+    undo_bar.connect_response(move |undo_bar, response| {
+        if response == gtk::ResponseType::Accept {
+            let _ = tx.send(UiEvent::UndoRemove);
+        }
+        undo_bar.hide();
+    });
+    // Only shown after an entry is removed, so keep the window's
+    // show_all from revealing it.
+    undo_bar.show_all();
+    undo_bar.hide();
+    undo_bar.set_no_show_all(true);
+    (undo_bar, undo_label)
+}
+
+/// Ask before doing something destructive, and send `event` if the
+/// user goes ahead.
+fn confirm_dialog(
+    parent: Option<&gtk::Window>,
+    text: &str,
+    secondary_text: &str,
+    confirm_label: &str,
+    event: UiEvent,
+    tx: glib::Sender<UiEvent>,
+) {
+    let dialog = gtk::MessageDialog::new(
+        parent,
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        gtk::MessageType::Warning,
+        gtk::ButtonsType::None,
+        text,
+    );
+    dialog.set_property_secondary_text(Some(secondary_text));
+    dialog.add_button("Cancel", gtk::ResponseType::Cancel);
+    dialog.add_button(confirm_label, gtk::ResponseType::Accept);
+    dialog.set_default_response(gtk::ResponseType::Cancel);
+    let event = Cell::new(Some(event));
+    dialog.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Accept {
+            if let Some(event) = event.take() {
+                let _ = tx.send(event);
+            }
+        }
+        dialog.close();
+    });
+    dialog.show_all();
+}
+
+fn setup_window(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> SetupWidgets {
     let page_stack = gtk::StackBuilder::new().build();
 
-    let (setup_box, otp_list) = setup_page(&app_state, tx);
+    let (setup_box, otp_list, group_order_combo) = setup_page(&app_state, tx.clone());
     page_stack.add_titled(&setup_box, "Setup", "Setup");
-    page_stack.add_titled(&about_page(), "About", "About");
+    let (trash_box, trash_list) = trash_page(&app_state, tx.clone());
+    page_stack.add_titled(&trash_box, "Trash", "Trash");
+    page_stack.add_titled(
+        &about_page(app_state.config_location.clone(), tx.clone()),
+        "About",
+        "About",
+    );
+    let (undo_bar, undo_label) = undo_bar(tx);
 
     let page_switcher = gtk::StackSwitcherBuilder::new().stack(&page_stack).build();
 
@@ -272,13 +1208,21 @@ fn setup_window(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> gtk::Lis
         .orientation(gtk::Orientation::Vertical)
         .build();
 
+    page_box.add(&undo_bar);
     page_box.add(&page_stack);
 
     let window = gtk::WindowBuilder::new().resizable(true).build();
+    let escape_search_entry = otp_list.search_entry.clone();
     window.connect_key_press_event(move |w, key_event| {
         match key_event.get_keyval().name() {
+            // Escape clears a search before it closes the window.
             Some(key_name) if key_name == "Escape" => {
-                w.close();
+                if escape_search_entry.get_text().is_empty() {
+                    w.close();
+                } else {
+                    escape_search_entry.set_text("");
+                    return Inhibit(true);
+                }
             }
             _ => {}
         }
@@ -291,23 +1235,85 @@ fn setup_window(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> gtk::Lis
     window.set_position(gtk::WindowPosition::Center);
     window.set_default_size(250, 200);
     window.show_all();
-    otp_list
+    otp_list.search_entry.grab_focus();
+    SetupWidgets {
+        otp_list,
+        group_order_combo,
+        trash_list,
+        undo_bar,
+        undo_label,
+    }
+}
+
+fn otp_menu_item(
+    app_state: &AppState,
+    new_app_state: &mut AppState,
+    entry: &OtpEntry,
+    tx: &glib::Sender<UiEvent>,
+) -> gtk::MenuItem {
+    let otp_value = entry.get_otp_value();
+    let otp_item = gtk::MenuItem::with_label(&app_state.formatted_menu_label(entry, &otp_value));
+    let menu_item_id = new_app_state.add_otp_value(&otp_item, entry.id, otp_value.otp.clone());
+    let copy_tx = tx.clone();
+    otp_item.connect_activate(move |_| {
+        let _ = copy_tx.send(UiEvent::CopyToClipboard(menu_item_id));
+    });
+    otp_item
 }
 
 fn build_menu(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> (AppState, gtk::Menu) {
     let menu = gtk::Menu::new();
 
     let mut new_app_state = app_state.menu_reset();
+    if app_state.locked {
+        let unlock_item = gtk::MenuItem::with_label("Unlock…");
+        let unlock_tx = tx.clone();
+        unlock_item.connect_activate(move |_| {
+            let _ = unlock_tx.send(UiEvent::Unlock);
+        });
+        menu.append(&unlock_item);
+        menu.append(&gtk::SeparatorMenuItem::new());
+        let quit_item = gtk::MenuItem::with_label("Quit");
+        quit_item.connect_activate(move |_| {
+            let _ = tx.send(UiEvent::Quit);
+        });
+        menu.append(&quit_item);
+        return (new_app_state, menu);
+    }
+
     if !app_state.otp_entries.is_empty() {
-        for entry in &app_state.otp_entries {
-            let otp_value = entry.get_otp_value();
-            let otp_item = gtk::MenuItem::with_label(&otp_value.formatted_menu_display());
-            let menu_item_id = new_app_state.add_otp_value(&otp_item, otp_value.otp.clone());
-            let copy_tx = tx.clone();
-            otp_item.connect_activate(move |_| {
-                let _ = copy_tx.send(UiEvent::CopyToClipboard(menu_item_id));
-            });
-            menu.append(&otp_item);
+        let pinned = app_state.pinned_entries();
+        for entry in &pinned {
+            menu.append(&otp_menu_item(&app_state, &mut new_app_state, entry, &tx));
+        }
+        if !pinned.is_empty() {
+            menu.append(&gtk::SeparatorMenuItem::new());
+        }
+        let recently_used = app_state.recently_used_entries();
+        if !recently_used.is_empty() {
+            menu.append(
+                &gtk::MenuItemBuilder::new()
+                    .label("Recently used")
+                    .sensitive(false)
+                    .build(),
+            );
+            for entry in recently_used {
+                menu.append(&otp_menu_item(&app_state, &mut new_app_state, entry, &tx));
+            }
+            menu.append(&gtk::SeparatorMenuItem::new());
+        }
+        let (ungrouped, grouped) = app_state.grouped_entries();
+        for entry in ungrouped {
+            menu.append(&otp_menu_item(&app_state, &mut new_app_state, entry, &tx));
+        }
+        for (group, entries) in grouped {
+            let group_menu = gtk::Menu::new();
+            for entry in entries {
+                group_menu.append(&otp_menu_item(&app_state, &mut new_app_state, entry, &tx));
+            }
+            let group_item = gtk::MenuItem::with_label(&group);
+            group_item.set_submenu(Some(&group_menu));
+            menu.append(&group_item);
         }
     } else {
         menu.append(&gtk::MenuItem::with_label(
@@ -317,6 +1323,30 @@ fn build_menu(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> (AppState,
 
     menu.append(&gtk::SeparatorMenuItem::new());
 
+    match app_state.config_location.profiles() {
+        Ok(profiles) if !profiles.is_empty() => {
+            let profile_menu = gtk::Menu::new();
+            let current_profile = app_state.config_location.profile.clone();
+            for profile in std::iter::once(None).chain(profiles.into_iter().map(Some)) {
+                let profile_item = gtk::CheckMenuItemBuilder::new()
+                    .label(profile.as_deref().unwrap_or(DEFAULT_PROFILE))
+                    .draw_as_radio(true)
+                    .active(profile == current_profile)
+                    .build();
+                let profile_tx = tx.clone();
+                profile_item.connect_activate(move |_| {
+                    let _ = profile_tx.send(UiEvent::SwitchProfile(profile.clone()));
+                });
+                profile_menu.append(&profile_item);
+            }
+            let profile_item = gtk::MenuItem::with_label("Profile");
+            profile_item.set_submenu(Some(&profile_menu));
+            menu.append(&profile_item);
+        }
+        Ok(_) => {}
+        Err(err) => log::error!("Failed to list profiles: {:?}", err),
+    }
+
     let setup_item = gtk::MenuItem::with_label("Setup");
     let setup_tx = tx.clone();
     setup_item.connect_activate(move |_| {
@@ -333,7 +1363,118 @@ fn build_menu(app_state: Arc<AppState>, tx: glib::Sender<UiEvent>) -> (AppState,
     (new_app_state, menu)
 }
 
-pub fn ui_main(global_app_state: Arc<AtomicImmut<AppState>>, _activation_policy: ActivationPolicy) {
+/// "GitHub" for a single entry, "3 entries" for several, or `None`
+/// if none of them exist anymore.
+fn formatted_entry_count(app_state: &AppState, entry_ids: &[Uuid]) -> Option<String> {
+    match entry_ids {
+        [entry_id] => app_state
+            .get_entry(*entry_id)
+            .map(|entry| entry.name.clone()),
+        [] => None,
+        _ => Some(format!("{} entries", entry_ids.len())),
+    }
+}
+
+/// Put `code` on the clipboard, and count it as a use of the entry.
+fn copy_code(
+    global_app_state: &AtomicImmut<AppState>,
+    entry_id: Uuid,
+    code: &str,
+    event_tx: &glib::Sender<UiEvent>,
+) {
+    let atom = gdk::Atom::intern("CLIPBOARD");
+    let clipboard = gtk::Clipboard::get(&atom);
+    clipboard.set_text(code);
+    match global_app_state
+        .load()
+        .mark_used(entry_id)
+        .save_usage_to_config()
+    {
+        Ok(app_state) => {
+            global_app_state.store(app_state);
+            // Picks up the new recently used and usage order.
+            let _ = event_tx.send(UiEvent::TotpRefresh);
+        }
+        Err(err) => log::warn!("Failed to record entry usage: {:?}", err),
+    }
+}
+
+fn reload_config(
+    global_app_state: &AtomicImmut<AppState>,
+    setup_widgets: &mut Option<SetupWidgets>,
+    event_tx: &glib::Sender<UiEvent>,
+) {
+    match AppState::load_from_config(&global_app_state.load().config_location) {
+        Ok(app_state) => {
+            if let Some(ref mut setup_widgets) = setup_widgets {
+                setup_widgets.refresh(&app_state);
+            }
+            global_app_state.store(app_state);
+            let _ = event_tx.send(UiEvent::TotpRefresh);
+        }
+        Err(err) => log::error!("Failed to reload configuration file: {:?}", err),
+    }
+}
+
+/// Save a changed state, and show it in the setup list and the tray.
+fn apply_change(
+    app_state: AppState,
+    global_app_state: &AtomicImmut<AppState>,
+    setup_widgets: &mut Option<SetupWidgets>,
+    tx: &glib::Sender<UiEvent>,
+) {
+    let app_state = app_state.save_or_reload();
+    if let Some(ref mut setup_widgets) = setup_widgets {
+        setup_widgets.refresh(&app_state);
+    }
+    global_app_state.store(app_state);
+    let _ = tx.send(UiEvent::TotpRefresh);
+}
+
+fn close_setup_window(setup_widgets: &mut Option<SetupWidgets>) {
+    if let Some(window) = setup_widgets.take().and_then(|widgets| widgets.window()) {
+        window.close();
+    }
+}
+
+fn watch_config(
+    config_location: &ConfigLocation,
+    tx: glib::Sender<UiEvent>,
+) -> Option<gio::FileMonitor> {
+    let config_file = match config_location.config_path() {
+        Ok(config_path) => gio::File::new_for_path(config_path),
+        Err(err) => {
+            log::warn!("Not watching configuration file: {:?}", err);
+            return None;
+        }
+    };
+    match config_file.monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>) {
+        Ok(monitor) => {
+            monitor.connect_changed(move |_, _, _, event| match event {
+                gio::FileMonitorEvent::ChangesDoneHint
+                | gio::FileMonitorEvent::Created
+                | gio::FileMonitorEvent::Deleted
+                | gio::FileMonitorEvent::MovedIn
+                | gio::FileMonitorEvent::Renamed => {
+                    let _ = tx.send(UiEvent::ConfigChanged);
+                }
+                _ => {}
+            });
+            Some(monitor)
+        }
+        Err(err) => {
+            log::warn!("Not watching configuration file: {:?}", err);
+            None
+        }
+    }
+}
+
+pub fn ui_main(
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    _activation_policy: ActivationPolicy,
+    instance_lock: Option<&InstanceLock>,
+    startup_events: Vec<UiEvent>,
+) {
     log::info!("Staring linux GTK ui main");
     gtk::init().unwrap();
 
@@ -350,14 +1491,60 @@ pub fn ui_main(global_app_state: Arc<AtomicImmut<AppState>>, _activation_policy:
     indicator.set_status(AppIndicatorStatus::Active);
     indicator.set_icon_full("otptray", "icon");
 
-    let mut otp_setup_list: Option<gtk::ListBox> = None;
+    let mut setup_widgets: Option<SetupWidgets> = None;
+    // Entry editors hold the secret in their buffers, so they're closed
+    // on lock along with the setup window.
+    let mut entry_windows: Vec<gtk::Window> = Vec::new();
+    let mut last_activity = Instant::now();
+
+    session::watch_session_lock(tx.clone());
+    let mut manager_signals = dbus::serve(global_app_state.clone(), tx.clone());
+    // Replaced whenever the profile changes, which drops and stops the
+    // previous monitor.
+    let mut _config_monitor = watch_config(&global_app_state.load().config_location, tx.clone());
+
+    if let Some(instance_lock) = instance_lock {
+        let instance_tx = tx.clone();
+        let instance_app_state = global_app_state.clone();
+        let listening = instance_lock.listen(move |request| {
+            let (response, event) = instance_app_state.load().handle_control_request(request)?;
+            if let Some(event) = event {
+                let _ = instance_tx.send(event);
+            }
+            Ok(response)
+        });
+        if let Err(err) = listening {
+            log::warn!("Not listening on the control socket: {:?}", err);
+        }
+    }
+    let holds_instance_lock = instance_lock.is_some();
+    for event in startup_events {
+        let _ = tx.send(event);
+    }
 
     let event_tx = tx.clone();
     rx.attach(None, move |event| {
         log::debug!("Got UI event: {:?}", event);
+        match event {
+            UiEvent::TotpRefresh | UiEvent::ConfigChanged | UiEvent::Lock => {}
+            _ => last_activity = Instant::now(),
+        }
+        if global_app_state.load().locked {
+            match event {
+                UiEvent::TotpRefresh | UiEvent::Lock | UiEvent::Unlock | UiEvent::Quit => {}
+                _ => {
+                    log::info!("Ignoring UI event while locked: {:?}", event);
+                    return Continue(true);
+                }
+            }
+        }
         match event {
             UiEvent::TotpRefresh => {
                 let app_state = global_app_state.load();
+                if app_state.idle_expired(last_activity.elapsed()) {
+                    let _ = event_tx.send(UiEvent::Lock);
+                }
+                manager_signals.update(&app_state);
                 let (new_app_state, mut menu) = build_menu(app_state, event_tx.clone());
                 global_app_state.store(new_app_state);
                 indicator.set_menu(&mut menu);
@@ -365,50 +1552,332 @@ pub fn ui_main(global_app_state: Arc<AtomicImmut<AppState>>, _activation_policy:
             }
             UiEvent::CopyToClipboard(menu_item_id) => {
                 let app_state = global_app_state.load();
-                if let Some(code) = app_state.get_otp_value_by_id(menu_item_id) {
-                    let atom = gdk::Atom::intern("CLIPBOARD");
-                    let clipboard = gtk::Clipboard::get(&atom);
-                    clipboard.set_text(code);
+                if let (Some(entry_id), Some(code)) = (
+                    app_state.get_entry_id_by_menu_id(menu_item_id),
+                    app_state.get_otp_value_by_id(menu_item_id),
+                ) {
+                    copy_code(&global_app_state, entry_id, code, &event_tx);
+                }
+            }
+            UiEvent::CopyEntry(entry_id) => {
+                let code = global_app_state
+                    .load()
+                    .get_entry(entry_id)
+                    .map(|entry| entry.get_otp_value().otp);
+                match code {
+                    Some(code) => copy_code(&global_app_state, entry_id, &code, &event_tx),
+                    None => log::warn!("Entry {} no longer exists", entry_id),
                 }
             }
             UiEvent::OpenSetup => {
-                let otp_list = setup_window(global_app_state.load(), event_tx.clone());
-                otp_setup_list = Some(otp_list);
+                setup_widgets = Some(setup_window(global_app_state.load(), event_tx.clone()));
+            }
+            UiEvent::OpenEntry(entry_action) => {
+                let window = match entry_action {
+                    EntryAction::Add => {
+                        otp_entry_window(&Default::default(), entry_action, event_tx.clone())
+                    }
+                    EntryAction::Edit(entry_id) => {
+                        match global_app_state.load().get_entry(entry_id) {
+                            Some(entry) => otp_entry_window(entry, entry_action, event_tx.clone()),
+                            None => {
+                                log::warn!("Entry {} no longer exists", entry_id);
+                                return Continue(true);
+                            }
+                        }
+                    }
+                };
+                entry_windows.retain(|window| window.is_visible());
+                entry_windows.push(window);
+            }
+            UiEvent::SaveEntry(entry, entry_action) => {
+                log::info!("Saving entry: {}", entry.name);
+                match global_app_state.load().save_entry(*entry, entry_action) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to save entry: {:?}", err),
+                }
+            }
+            UiEvent::ConfirmRemoveEntries(entry_ids) => {
+                let app_state = global_app_state.load();
+                match formatted_entry_count(&app_state, &entry_ids) {
+                    Some(removed) => confirm_dialog(
+                        setup_widgets
+                            .as_ref()
+                            .and_then(|widgets| widgets.window())
+                            .as_ref(),
+                        &format!("Move {} to the trash?", removed),
+                        &format!(
+                            "{} can be restored from the Trash page for {} days.",
+                            if entry_ids.len() == 1 { "It" } else { "They" },
+                            app_state.trash_retention().num_days()
+                        ),
+                        "Move to Trash",
+                        UiEvent::RemoveEntries(entry_ids),
+                        event_tx.clone(),
+                    ),
+                    None => log::warn!("Entries {:?} no longer exist", entry_ids),
+                }
+            }
+            UiEvent::RemoveEntries(entry_ids) => {
+                log::info!("Moving entries to the trash: {:?}", entry_ids);
+                let app_state = global_app_state.load();
+                let removed = formatted_entry_count(&app_state, &entry_ids);
+                match app_state.remove_entries(&entry_ids) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx);
+                        if let (Some(setup_widgets), Some(removed)) = (&setup_widgets, removed) {
+                            setup_widgets.show_undo(&removed);
+                        }
+                    }
+                    Err(err) => log::error!("Failed to remove entries: {:?}", err),
+                }
+            }
+            UiEvent::UndoRemove => {
+                log::info!("Undoing last entry removal");
+                match global_app_state.load().undo_remove() {
+                    Ok(Some(app_state)) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Ok(None) => log::info!("Nothing to undo"),
+                    Err(err) => log::error!("Failed to undo entry removal: {:?}", err),
+                }
+            }
+            UiEvent::RestoreEntry(entry_id) => {
+                log::info!("Restoring entry from the trash: {}", entry_id);
+                match global_app_state.load().restore_entry(entry_id) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to restore entry: {:?}", err),
+                }
+            }
+            UiEvent::ConfirmPurgeEntry(entry_id) => {
+                let app_state = global_app_state.load();
+                match app_state
+                    .trash
+                    .iter()
+                    .find(|trashed| trashed.entry.id == entry_id)
+                {
+                    Some(trashed) => confirm_dialog(
+                        setup_widgets
+                            .as_ref()
+                            .and_then(|widgets| widgets.window())
+                            .as_ref(),
+                        &format!("Permanently delete {}?", trashed.entry.name),
+                        "Its secret can't be recovered afterwards.",
+                        "Delete Permanently",
+                        UiEvent::PurgeEntry(entry_id),
+                        event_tx.clone(),
+                    ),
+                    None => log::warn!("Entry {} is no longer in the trash", entry_id),
+                }
+            }
+            UiEvent::PurgeEntry(entry_id) => {
+                log::info!("Permanently deleting entry: {}", entry_id);
+                match global_app_state.load().purge_entry(entry_id) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to delete entry: {:?}", err),
+                }
+            }
+            UiEvent::TogglePinned(entry_id) => {
+                let app_state = global_app_state.load();
+                let pinned = app_state.get_entry(entry_id).map(|entry| !entry.pinned);
+                match pinned.map(|pinned| app_state.set_pinned(entry_id, pinned)) {
+                    Some(Ok(app_state)) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Some(Err(err)) => log::error!("Failed to pin entry: {:?}", err),
+                    None => log::warn!("Entry {} no longer exists", entry_id),
+                }
+            }
+            UiEvent::MoveEntryBy(entry_id, offset) => {
+                match global_app_state.load().move_entry_by(entry_id, offset) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to move entry: {:?}", err),
+                }
             }
-            UiEvent::OpenEntry(entry_action) => match entry_action {
-                EntryAction::Add => {
-                    otp_entry_window(&Default::default(), entry_action, event_tx.clone())
+            UiEvent::MoveEntryTo(entry_id, target_id) => {
+                match global_app_state.load().move_entry_to(entry_id, target_id) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to move entry: {:?}", err),
                 }
-                EntryAction::Edit(selected_row) => otp_entry_window(
-                    &global_app_state.load().otp_entries[selected_row],
-                    entry_action,
+            }
+            UiEvent::SetMenuOrder(menu_order) => {
+                log::info!("Sorting tray menu by: {:?}", menu_order);
+                let app_state = global_app_state.load().set_menu_order(menu_order);
+                apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx);
+            }
+            UiEvent::OpenGroup(group_action) => {
+                let app_state = global_app_state.load();
+                // Preselect the entries' group when they all share one.
+                let current_group = match group_action {
+                    GroupAction::MoveEntries(ref entry_ids) => {
+                        let mut groups = entry_ids
+                            .iter()
+                            .filter_map(|entry_id| app_state.get_entry(*entry_id))
+                            .map(|entry| entry.group.clone());
+                        let first = groups.next().flatten();
+                        if groups.all(|group| group == first) {
+                            first
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                group_window(
+                    &app_state.all_groups(),
+                    current_group.as_deref(),
+                    group_action,
                     event_tx.clone(),
-                ),
-            },
-            UiEvent::SaveEntry(entry, entry_action) => {
-                log::info!("Saving: {:?}", entry);
-                let app_state = global_app_state.load().save_entry(entry, entry_action);
-                if let Some(ref mut otp_list) = otp_setup_list {
-                    build_otp_list(otp_list, &app_state.otp_entries);
+                );
+            }
+            UiEvent::CreateGroup(name) => {
+                log::info!("Creating group: {}", name);
+                match global_app_state.load().create_group(&name) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to create group: {:?}", err),
                 }
-                if let Err(err) = app_state.save_to_config() {
-                    log::error!("Failed to save configuration file: {:?}", err);
+            }
+            UiEvent::RenameGroup(name, new_name) => {
+                log::info!("Renaming group {} to {}", name, new_name);
+                match global_app_state.load().rename_group(&name, &new_name) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to rename group: {:?}", err),
+                }
+            }
+            UiEvent::MoveGroupBy(name, offset) => {
+                match global_app_state.load().move_group_by(&name, offset) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to move group: {:?}", err),
                 }
-                global_app_state.store(app_state);
-                let _ = event_tx.send(UiEvent::TotpRefresh);
             }
-            UiEvent::RemoveEntry(selected_row) => {
-                log::info!("Removing entry at index: {}", selected_row);
-                let app_state = global_app_state.load().remove_entry_index(selected_row);
-                if let Some(ref mut otp_list) = otp_setup_list {
-                    build_otp_list(otp_list, &app_state.otp_entries);
+            UiEvent::DeleteGroup(name) => {
+                log::info!("Deleting group: {}", name);
+                match global_app_state.load().delete_group(&name) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to delete group: {:?}", err),
                 }
-                if let Err(err) = app_state.save_to_config() {
-                    log::error!("Failed to save configuration file: {:?}", err);
+            }
+            UiEvent::MoveToGroup(entry_ids, group) => {
+                log::info!("Moving entries {:?} to group {:?}", entry_ids, group);
+                match global_app_state
+                    .load()
+                    .move_entries_to_group(&entry_ids, group)
+                {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to move entries: {:?}", err),
+                }
+            }
+            UiEvent::OpenBulkEdit(entry_ids) => {
+                bulk_edit_window(entry_ids, event_tx.clone());
+            }
+            UiEvent::BulkEditEntries(entry_ids, bulk_edit) => {
+                log::info!("Editing entries {:?}: {:?}", entry_ids, bulk_edit);
+                match global_app_state.load().bulk_edit(&entry_ids, &bulk_edit) {
+                    Ok(app_state) => {
+                        apply_change(app_state, &global_app_state, &mut setup_widgets, &event_tx)
+                    }
+                    Err(err) => log::error!("Failed to edit entries: {:?}", err),
+                }
+            }
+            UiEvent::ExportEntries(entry_ids, path) => {
+                log::info!("Exporting {} entries to {:?}", entry_ids.len(), path);
+                if let Err(err) = global_app_state.load().export_entries(&entry_ids, &path) {
+                    log::error!("Failed to export entries: {:?}", err);
+                }
+            }
+            UiEvent::RestoreBackup(backup_path) => {
+                log::info!("Restoring configuration backup: {:?}", backup_path);
+                match global_app_state.load().restore_backup(&backup_path) {
+                    Ok(app_state) => {
+                        if let Some(ref mut setup_widgets) = setup_widgets {
+                            setup_widgets.refresh(&app_state);
+                        }
+                        global_app_state.store(app_state);
+                        let _ = event_tx.send(UiEvent::TotpRefresh);
+                    }
+                    Err(err) => log::error!("Failed to restore configuration backup: {:?}", err),
+                }
+            }
+            UiEvent::ConfigChanged => {
+                let app_state = global_app_state.load();
+                match app_state.config_unchanged() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::info!("Configuration file changed on disk, reloading");
+                        reload_config(&global_app_state, &mut setup_widgets, &event_tx);
+                    }
+                    Err(err) => log::error!("Failed to read configuration file: {:?}", err),
+                }
+            }
+            UiEvent::Reload => {
+                log::info!("Reloading configuration file");
+                reload_config(&global_app_state, &mut setup_widgets, &event_tx);
+            }
+            UiEvent::SwitchProfile(profile) => {
+                let config_location = global_app_state
+                    .load()
+                    .config_location
+                    .with_profile(profile);
+                match config_location.and_then(|location| AppState::load_from_config(&location)) {
+                    Ok(app_state) => {
+                        log::info!(
+                            "Switched to profile: {}",
+                            app_state.config_location.profile_name()
+                        );
+                        close_setup_window(&mut setup_widgets);
+                        _config_monitor =
+                            watch_config(&app_state.config_location, event_tx.clone());
+                        if holds_instance_lock {
+                            if let Err(err) = instance::record_config(&app_state.config_location) {
+                                log::warn!("Not recording the new profile's config: {:?}", err);
+                            }
+                        }
+                        global_app_state.store(app_state);
+                        let _ = event_tx.send(UiEvent::TotpRefresh);
+                    }
+                    Err(err) => log::error!("Failed to switch profile: {:?}", err),
+                }
+            }
+            UiEvent::Lock => {
+                log::info!("Locking, clearing secrets from memory");
+                let app_state = global_app_state.load().lock();
+                close_setup_window(&mut setup_widgets);
+                for window in entry_windows.drain(..) {
+                    window.close();
                 }
                 global_app_state.store(app_state);
                 let _ = event_tx.send(UiEvent::TotpRefresh);
             }
+            UiEvent::Unlock => {
+                log::info!("Unlocking, reloading configuration file");
+                match AppState::load_from_config(&global_app_state.load().config_location) {
+                    Ok(app_state) => {
+                        global_app_state.store(app_state);
+                        let _ = event_tx.send(UiEvent::TotpRefresh);
+                    }
+                    Err(err) => log::error!("Failed to reload configuration file: {:?}", err),
+                }
+            }
             UiEvent::Quit => {
                 gtk::main_quit();
             }
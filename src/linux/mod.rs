@@ -1 +1,3 @@
+pub mod dbus;
 pub mod gui;
+pub mod session;
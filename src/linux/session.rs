@@ -0,0 +1,115 @@
+use std::thread;
+
+use zbus::fdo::DBusProxy;
+use zbus::Connection;
+use zvariant::OwnedObjectPath;
+
+use crate::common::*;
+
+const LOGIND_BUS_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+const LOGIND_SESSION: &str = "org.freedesktop.login1.Session";
+const SCREENSAVER: &str = "org.freedesktop.ScreenSaver";
+
+/// Watch for the desktop session being locked or the machine going
+/// to sleep, and send a `UiEvent::Lock` whenever either happens.
+/// Each bus is watched from its own thread, since zbus blocks while
+/// waiting for messages.
+pub fn watch_session_lock(tx: glib::Sender<UiEvent>) {
+    let logind_tx = tx.clone();
+    thread::spawn(move || {
+        if let Err(err) = watch_logind(logind_tx) {
+            log::warn!("Not watching logind for lock / suspend: {:?}", err);
+        }
+    });
+    thread::spawn(move || {
+        if let Err(err) = watch_screensaver(tx) {
+            log::warn!("Not watching the screensaver for lock: {:?}", err);
+        }
+    });
+}
+
+fn watch_logind(tx: glib::Sender<UiEvent>) -> zbus::Result<()> {
+    let connection = Connection::new_system()?;
+    let dbus = DBusProxy::new(&connection)?;
+    dbus.add_match(&format!(
+        "type='signal',sender='{}',interface='{}',member='PrepareForSleep'",
+        LOGIND_BUS_NAME, LOGIND_MANAGER
+    ))?;
+
+    // Only follow our own session if logind knows which one that
+    // is. Otherwise, any session lock on the seat is good enough.
+    let session_rule = match current_session(&connection) {
+        Ok(session) => format!(
+            "type='signal',sender='{}',interface='{}',member='Lock',path='{}'",
+            LOGIND_BUS_NAME,
+            LOGIND_SESSION,
+            session.as_str()
+        ),
+        Err(err) => {
+            log::debug!("Could not find current logind session: {:?}", err);
+            format!(
+                "type='signal',sender='{}',interface='{}',member='Lock'",
+                LOGIND_BUS_NAME, LOGIND_SESSION
+            )
+        }
+    };
+    dbus.add_match(&session_rule)?;
+
+    loop {
+        let message = connection.receive_message()?;
+        let header = message.header()?;
+        let locking = match (header.interface()?, header.member()?) {
+            (Some(LOGIND_MANAGER), Some("PrepareForSleep")) => match message.body::<bool>() {
+                Ok(sleeping) => sleeping,
+                Err(err) => {
+                    log::warn!("Ignoring malformed PrepareForSleep signal: {:?}", err);
+                    false
+                }
+            },
+            (Some(LOGIND_SESSION), Some("Lock")) => true,
+            _ => false,
+        };
+        if locking {
+            log::info!("Locking on logind {:?}", header.member()?);
+            let _ = tx.send(UiEvent::Lock);
+        }
+    }
+}
+
+fn current_session(connection: &Connection) -> zbus::Result<OwnedObjectPath> {
+    let reply = connection.call_method(
+        Some(LOGIND_BUS_NAME),
+        LOGIND_PATH,
+        Some(LOGIND_MANAGER),
+        "GetSessionByPID",
+        &(std::process::id()),
+    )?;
+    Ok(reply.body::<OwnedObjectPath>()?)
+}
+
+fn watch_screensaver(tx: glib::Sender<UiEvent>) -> zbus::Result<()> {
+    let connection = Connection::new_session()?;
+    let dbus = DBusProxy::new(&connection)?;
+    dbus.add_match(&format!(
+        "type='signal',interface='{}',member='ActiveChanged'",
+        SCREENSAVER
+    ))?;
+
+    loop {
+        let message = connection.receive_message()?;
+        let header = message.header()?;
+        if header.interface()? != Some(SCREENSAVER) || header.member()? != Some("ActiveChanged") {
+            continue;
+        }
+        match message.body::<bool>() {
+            Ok(true) => {
+                log::info!("Locking on screensaver activation");
+                let _ = tx.send(UiEvent::Lock);
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!("Ignoring malformed ActiveChanged signal: {:?}", err),
+        }
+    }
+}
@@ -3,8 +3,10 @@ use core::ffi::c_void;
 use log;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
+use uuid::Uuid;
 
 use crate::common::*;
+use crate::instance::InstanceLock;
 
 use cocoa::appkit::{
     NSApp, NSApplication, NSBackingStoreType, NSButton, NSMenu, NSMenuItem, NSPasteboard,
@@ -119,7 +121,8 @@ impl EventResponder {
                 2 => responder
                     .otp_setup_list
                     .selected_item
-                    .map(|selected| UiEvent::RemoveEntry(selected)),
+                    .map(|selected| UiEvent::RemoveEntries(vec![selected])),
+                3 => Some(UiEvent::UndoRemove),
                 _ => None,
             } {
                 let _ = &responder.tx.send(event);
@@ -183,7 +186,8 @@ lazy_static! {
 struct OtpSetupList {
     app_state: Arc<AppState>,
     obj_c_setup_list: Option<StrongPtr>,
-    selected_item: Option<usize>,
+    table_view: Option<StrongPtr>,
+    selected_item: Option<Uuid>,
 }
 
 impl OtpSetupList {
@@ -191,10 +195,25 @@ impl OtpSetupList {
         Self {
             app_state,
             obj_c_setup_list: None,
+            table_view: None,
             selected_item: None,
         }
     }
 
+    /// Show the entries of `app_state`, such as after some were
+    /// removed. Rows can point at other entries afterwards, so the
+    /// selection is cleared.
+    fn refresh(&mut self, app_state: Arc<AppState>) {
+        self.app_state = app_state;
+        self.selected_item = None;
+        if let Some(table_view) = &self.table_view {
+            unsafe {
+                let _: () = msg_send![**table_view, reloadData];
+                let _: () = msg_send![**table_view, deselectAll: nil];
+            }
+        }
+    }
+
     fn instantiate_obj_c_setup_list(&mut self) {
         let obj_c_setup_list: id = unsafe { msg_send![*OTP_SETUP_LIST_CLASS, new] };
         unsafe {
@@ -239,7 +258,11 @@ impl OtpSetupList {
             let mut setup_list = Self::rust_setup_list(this);
             setup_list.selected_item = match selected_row_index {
                 -1 => None,
-                index => Some(index as usize),
+                index => setup_list
+                    .app_state
+                    .otp_entries
+                    .get(index as usize)
+                    .map(|entry| entry.id),
             };
             log::debug!("Got selection change. Row index: {}", selected_row_index);
         }
@@ -277,6 +300,7 @@ fn setup_page(event_responder: &mut EventResponder, frame: NSRect) -> id {
         let _: () = msg_send![table_view, setDataSource: **otp_objc];
         let _: () = msg_send![table_view, setDelegate: **otp_objc];
         table_view.autorelease();
+        event_responder.otp_setup_list.table_view = Some(StrongPtr::retain(table_view));
 
         let column: id = msg_send![class!(NSTableColumn), alloc];
         let _: () = msg_send![column, initWithIdentifier: NSString::alloc(nil).init_str("Name").autorelease() ];
@@ -295,14 +319,16 @@ fn setup_page(event_responder: &mut EventResponder, frame: NSRect) -> id {
         let add_label: id = NSString::alloc(nil).init_str("Add").autorelease();
         let edit_label: id = NSString::alloc(nil).init_str("Edit").autorelease();
         let remove_label: id = NSString::alloc(nil).init_str("Remove").autorelease();
+        let undo_label: id = NSString::alloc(nil).init_str("Undo").autorelease();
         let button_segment: id = msg_send![class!(NSSegmentedControl), alloc];
         let _: () = msg_send![button_segment, initWithFrame: NSRect::new(NSPoint::new(0.0, 0.0), NSSize::new(frame.size.width, 10.0))];
         let _: () = msg_send![button_segment, setTarget: **event_responder_objc];
         let _: () = msg_send![button_segment, setAction: sel!(open_entry:)];
-        let _: () = msg_send![button_segment, setSegmentCount: 3];
+        let _: () = msg_send![button_segment, setSegmentCount: 4];
         let _: () = msg_send![button_segment, setLabel: add_label forSegment: 0 ];
         let _: () = msg_send![button_segment, setLabel: edit_label forSegment: 1 ];
         let _: () = msg_send![button_segment, setLabel: remove_label forSegment: 2 ];
+        let _: () = msg_send![button_segment, setLabel: undo_label forSegment: 3 ];
         let _: () = msg_send![button_segment, sizeToFit];
         let _: () = msg_send![table_box, addSubview: button_segment];
         button_segment.autorelease();
@@ -421,7 +447,7 @@ fn build_menu(app_state: Arc<AppState>, event_responder: &EventResponder) -> (Ap
             let action = sel!(menu_selected:);
             let otp_value = entry.get_otp_value();
             let entry_label = NSString::alloc(nil)
-                .init_str(&otp_value.formatted_menu_display())
+                .init_str(&app_state.formatted_menu_label(entry, &otp_value))
                 .autorelease();
             let entry_item = NSMenuItem::alloc(nil)
                 .initWithTitle_action_keyEquivalent_(
@@ -480,6 +506,22 @@ fn process_events(event_responder: &mut EventResponder) {
                     copy_to_pasteboard(&otp_value.otp);
                 }
             }
+            UiEvent::CopyEntry(entry_id) => {
+                match event_responder.global_app_state.load().get_entry(entry_id) {
+                    Some(entry) => copy_to_pasteboard(&entry.get_otp_value().otp),
+                    None => log::warn!("Entry {} no longer exists", entry_id),
+                }
+            }
+            UiEvent::Reload => {
+                let app_state = event_responder.global_app_state.load();
+                match AppState::load_from_config(&app_state.config_location) {
+                    Ok(app_state) => {
+                        event_responder.global_app_state.store(app_state);
+                        let _ = event_responder.tx.send(UiEvent::TotpRefresh);
+                    }
+                    Err(err) => log::error!("Failed to reload configuration file: {:?}", err),
+                }
+            }
             UiEvent::OpenSetup => unsafe {
                 let app = NSApplication::sharedApplication(nil);
                 let window = setup_window(event_responder);
@@ -495,8 +537,27 @@ fn process_events(event_responder: &mut EventResponder) {
                         otp_entry_window(&Default::default(), entry_action, event_responder);
                     window.makeKeyAndOrderFront_(app);
                 },
-                EntryAction::Edit(selected_row) => {}
+                EntryAction::Edit(_entry_id) => {}
             },
+            UiEvent::RemoveEntries(entry_ids) => {
+                log::info!("Moving entries to the trash: {:?}", entry_ids);
+                match event_responder
+                    .global_app_state
+                    .load()
+                    .remove_entries(&entry_ids)
+                {
+                    Ok(app_state) => apply_change(app_state, event_responder),
+                    Err(err) => log::error!("Failed to remove entries: {:?}", err),
+                }
+            }
+            UiEvent::UndoRemove => {
+                log::info!("Undoing last entry removal");
+                match event_responder.global_app_state.load().undo_remove() {
+                    Ok(Some(app_state)) => apply_change(app_state, event_responder),
+                    Ok(None) => log::info!("Nothing to undo"),
+                    Err(err) => log::error!("Failed to undo entry removal: {:?}", err),
+                }
+            }
             UiEvent::Quit => {
                 unsafe {
                     let app = NSApplication::sharedApplication(nil);
@@ -531,6 +592,17 @@ fn process_events(event_responder: &mut EventResponder) {
     }
 }
 
+/// Save a changed state, and show it in the setup list and the tray.
+fn apply_change(app_state: AppState, event_responder: &mut EventResponder) {
+    event_responder
+        .global_app_state
+        .store(app_state.save_or_reload());
+    event_responder
+        .otp_setup_list
+        .refresh(event_responder.global_app_state.load());
+    let _ = event_responder.tx.send(UiEvent::TotpRefresh);
+}
+
 fn start_timer(event_responder: &EventResponder) {
     unsafe {
         let _: () = msg_send![class!(NSTimer),
@@ -542,9 +614,28 @@ fn start_timer(event_responder: &EventResponder) {
     }
 }
 
-pub fn ui_main(global_app_state: Arc<AtomicImmut<AppState>>, activation_policy: ActivationPolicy) {
+pub fn ui_main(
+    global_app_state: Arc<AtomicImmut<AppState>>,
+    activation_policy: ActivationPolicy,
+    instance_lock: Option<&InstanceLock>,
+    startup_events: Vec<UiEvent>,
+) {
     log::info!("Staring macOS ui main");
     let (tx, rx) = channel();
+    if let Some(instance_lock) = instance_lock {
+        let instance_tx = tx.clone();
+        let instance_app_state = global_app_state.clone();
+        let listening = instance_lock.listen(move |request| {
+            let (response, event) = instance_app_state.load().handle_control_request(request)?;
+            if let Some(event) = event {
+                let _ = instance_tx.send(event);
+            }
+            Ok(response)
+        });
+        if let Err(err) = listening {
+            log::warn!("Not listening on the control socket: {:?}", err);
+        }
+    }
     let mut event_responder = EventResponder::new(global_app_state, tx.clone(), rx);
     event_responder.instantiate_obj_c_responder();
 
@@ -554,6 +645,9 @@ pub fn ui_main(global_app_state: Arc<AtomicImmut<AppState>>, activation_policy:
             app.setActivationPolicy_(cocoa::appkit::NSApplicationActivationPolicyRegular);
         }
         let _ = tx.send(UiEvent::TotpRefresh);
+        for event in startup_events {
+            let _ = tx.send(event);
+        }
         process_events(&mut event_responder);
         start_timer(&event_responder);
         app.run();
@@ -8,9 +8,15 @@ extern crate objc;
 use atomic_immut::AtomicImmut;
 use clap::{App, Arg};
 use simple_logger::SimpleLogger;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+mod cli;
 pub mod common;
+mod instance;
+mod pick;
+pub mod secret;
+mod tui;
 
 #[cfg(target_os = "linux")]
 mod linux;
@@ -23,6 +29,7 @@ mod macos;
 use macos::gui;
 
 use crate::common::*;
+use crate::instance::InstanceLock;
 
 lazy_static! {
     static ref APP_STATE: Arc<AtomicImmut<AppState>> =
@@ -38,15 +45,103 @@ fn main() {
                 .help("Whether to launch the application in the foreground or not (OS X only)")
                 .short("f"),
         )
+        .arg(
+            Arg::with_name("config")
+                .help("Path to the config file of the default profile [env: OTPTRAY_CONFIG]")
+                .long("config")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help("Named profile to start with, created on first save if it doesn't exist")
+                .long("profile")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("setup")
+                .help("Open the setup window, in the running otptray if there is one")
+                .long("setup"),
+        )
+        .arg(
+            Arg::with_name("reload")
+                .help("Make the running otptray reload its config")
+                .long("reload"),
+        )
+        .subcommands(cli::subcommands())
         .get_matches();
     let activation_policy = if matches.is_present("foreground") {
         ActivationPolicy::Foreground
     } else {
         ActivationPolicy::Background
     };
+    let config_location = match ConfigLocation::from_env(
+        matches.value_of("config").map(PathBuf::from),
+        matches.value_of("profile").map(String::from),
+    ) {
+        Ok(config_location) => config_location,
+        Err(err) => {
+            eprintln!("otptray: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let (subcommand, Some(subcommand_matches)) = matches.subcommand() {
+        // The logger writes to stdout, so it stays off for subcommands
+        // whose output is meant for scripts.
+        if let Err(err) = cli::run(subcommand, subcommand_matches, &config_location) {
+            eprintln!("otptray: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     SimpleLogger::new().init().unwrap();
-    let app_state = AppState::load_from_config().expect("Cannot load OTPTrap config!");
+
+    let forwarded_command = if matches.is_present("setup") {
+        Some(ControlRequest::OpenSetup)
+    } else if matches.is_present("reload") {
+        Some(ControlRequest::Reload)
+    } else {
+        None
+    };
+    let instance_lock = match InstanceLock::acquire() {
+        Ok(Some(instance_lock)) => {
+            if let Err(err) = instance::record_config(&config_location) {
+                log::warn!("Not recording which config this otptray uses: {:?}", err);
+            }
+            Some(instance_lock)
+        }
+        Ok(None) => {
+            let result = match forwarded_command {
+                Some(request) => instance::forward_request(&config_location, &request).map(drop),
+                None => instance::check_running_config(&config_location)
+                    .map(|()| eprintln!("otptray is already running")),
+            };
+            if let Err(err) = result {
+                eprintln!("otptray: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Err(err) => {
+            log::warn!("Not checking for another running otptray: {:?}", err);
+            None
+        }
+    };
+    let startup_events = match forwarded_command {
+        Some(ControlRequest::OpenSetup) => vec![UiEvent::OpenSetup],
+        _ => Vec::new(),
+    };
+    let app_state =
+        AppState::load_from_config(&config_location).expect("Cannot load OTPTrap config!");
     APP_STATE.store(app_state);
 
-    gui::ui_main(APP_STATE.clone(), activation_policy);
+    gui::ui_main(
+        APP_STATE.clone(),
+        activation_policy,
+        instance_lock.as_ref(),
+        startup_events,
+    );
 }
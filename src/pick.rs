@@ -0,0 +1,153 @@
+//! Picking an entry with a dmenu-style launcher, for desktops without
+//! a tray. The code is copied with `wl-copy` or `xclip`, or typed
+//! with `wtype` or `xdotool`.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+use crate::common::*;
+
+pub const DEFAULT_LAUNCHER: &str = "dmenu -i -p otptray";
+
+/// Separates rofi script mode row options, as in `name\0info\x1fid`.
+const ROFI_OPTION_SEPARATOR: char = '\x1f';
+
+/// Show entry names in `launcher`, then copy or type the code of the
+/// one picked. Closing the launcher without picking does nothing.
+pub fn pick(app_state: &AppState, launcher: &str, type_code: bool) -> Result<(), Error> {
+    let names = app_state
+        .otp_entries
+        .iter()
+        .map(|entry| entry.name.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(launcher)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| command_error(launcher, err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A launcher that exits before reading everything, like one
+        // closed right away, is the same as nothing being picked.
+        match stdin.write_all(names.as_bytes()) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+            _ => {}
+        }
+    }
+    let mut selection = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_string(&mut selection)?;
+    }
+    if !child.wait()?.success() || selection.trim().is_empty() {
+        return Ok(());
+    }
+    let entry = app_state.find_entry_by_name(selection.trim())?;
+    use_code(app_state, entry, type_code)
+}
+
+/// rofi's script mode protocol, for `rofi -show otp -modi
+/// "otp:otptray pick --rofi-script"`. rofi runs the script without a
+/// selection to list the rows, then again with the row picked.
+pub fn rofi_script(app_state: &AppState, selection: Option<&str>) -> Result<(), Error> {
+    let selection = match selection {
+        Some(selection) => selection,
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            writeln!(stdout, "\0prompt{}otptray", ROFI_OPTION_SEPARATOR)?;
+            for entry in &app_state.otp_entries {
+                // The id comes back as ROFI_INFO, so entries with the
+                // same name are told apart.
+                writeln!(
+                    stdout,
+                    "{}\0info{}{}",
+                    entry.name, ROFI_OPTION_SEPARATOR, entry.id
+                )?;
+            }
+            return Ok(());
+        }
+    };
+    let entry = match env::var("ROFI_INFO")
+        .ok()
+        .and_then(|info| Uuid::parse_str(&info).ok())
+        .and_then(|id| app_state.get_entry(id))
+    {
+        Some(entry) => entry,
+        None => app_state.find_entry_by_name(selection)?,
+    };
+    use_code(app_state, entry, false)
+}
+
+fn use_code(app_state: &AppState, entry: &OtpEntry, type_code: bool) -> Result<(), Error> {
+    let otp = entry.get_otp_value().otp;
+    if type_code {
+        type_text(&otp)?;
+    } else {
+        copy_to_clipboard(&otp)?;
+    }
+    if let Err(err) = app_state.record_use(entry.id) {
+        log::warn!("Couldn't save usage: {}", err);
+    }
+    Ok(())
+}
+
+fn is_wayland() -> bool {
+    env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+fn copy_to_clipboard(text: &str) -> Result<(), Error> {
+    let (program, args): (&str, &[&str]) = if is_wayland() {
+        ("wl-copy", &[])
+    } else {
+        ("xclip", &["-selection", "clipboard"])
+    };
+    // In rofi script mode, stdout goes to rofi, which waits for it to
+    // close. The clipboard helper stays running to serve the clipboard,
+    // so it must not hold on to it.
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| command_error(program, err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    check_status(program, child.wait()?)
+}
+
+/// Type `text` into the focused window, for fields that don't take
+/// pastes.
+fn type_text(text: &str) -> Result<(), Error> {
+    let (program, args): (&str, &[&str]) = if is_wayland() {
+        ("wtype", &["--"])
+    } else {
+        ("xdotool", &["type", "--clearmodifiers", "--"])
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg(text)
+        .status()
+        .map_err(|err| command_error(program, err))?;
+    check_status(program, status)
+}
+
+fn check_status(program: &str, status: std::process::ExitStatus) -> Result<(), Error> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} failed: {}", program, status),
+        )
+        .into())
+    }
+}
+
+fn command_error(program: &str, err: io::Error) -> Error {
+    io::Error::new(err.kind(), format!("couldn't run {}: {}", program, err)).into()
+}
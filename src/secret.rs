@@ -0,0 +1,277 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use zeroize::Zeroize;
+
+lazy_static! {
+    /// How many live secrets sit on each locked page, by page address.
+    /// `mlock` works on whole pages and doesn't count, so a page is only
+    /// unlocked once no secret is left on it.
+    static ref LOCKED_PAGES: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+}
+
+/// A TOTP secret. The contents never show up in `Debug` or `Display`
+/// output, and are wiped from memory once the last copy is dropped.
+///
+/// Clones share the same allocation, so copying `AppState` around
+/// doesn't scatter copies of the secret across the heap.
+#[derive(Clone, Default)]
+pub struct Secret(Arc<LockedString>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Arc::new(LockedString::new(value)))
+    }
+
+    /// Borrow the raw secret. Keep the borrow short, and never log it.
+    pub fn expose(&self) -> &str {
+        &(self.0).0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expose().is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.expose() == other.expose()
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// The heap buffer behind a `Secret`. It is never resized after
+/// creation, so the pages we `mlock` stay the ones holding the secret.
+#[derive(Default)]
+struct LockedString(String);
+
+impl LockedString {
+    fn new(mut value: String) -> Self {
+        // Shrinking `value` in place could move the secret and free the
+        // old buffer without wiping it. Instead it's copied into a
+        // buffer of exactly its size, locked before the copy, and the
+        // original is wiped.
+        let mut locked = String::with_capacity(value.len());
+        lock_memory(locked.as_ptr(), locked.capacity());
+        locked.push_str(&value);
+        value.zeroize();
+        LockedString(locked)
+    }
+}
+
+impl Drop for LockedString {
+    fn drop(&mut self) {
+        let (ptr, len) = (self.0.as_ptr(), self.0.capacity());
+        self.0.zeroize();
+        unlock_memory(ptr, len);
+    }
+}
+
+#[cfg(unix)]
+fn lock_memory(ptr: *const u8, len: usize) {
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        if let Some(count) = locked_pages.get_mut(&page) {
+            *count += 1;
+            continue;
+        }
+        // Locking can fail when RLIMIT_MEMLOCK is exhausted. The secret
+        // is still zeroized on drop, it just might end up in swap.
+        if unsafe { libc::mlock(page as *const libc::c_void, page_size()) } != 0 {
+            log::debug!(
+                "Could not lock secret in memory: {}",
+                std::io::Error::last_os_error()
+            );
+            continue;
+        }
+        locked_pages.insert(page, 1);
+    }
+}
+
+#[cfg(unix)]
+fn unlock_memory(ptr: *const u8, len: usize) {
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        match locked_pages.get_mut(&page) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                locked_pages.remove(&page);
+                unsafe {
+                    libc::munlock(page as *const libc::c_void, page_size());
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Addresses of the pages `len` bytes from `ptr` are on.
+#[cfg(unix)]
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let page_size = page_size();
+    let start = ptr as usize / page_size * page_size;
+    let end = if len == 0 { start } else { ptr as usize + len };
+    (start..end).step_by(page_size)
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn lock_memory(_ptr: *const u8, _len: usize) {}
+
+#[cfg(not(unix))]
+fn unlock_memory(_ptr: *const u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::*;
+    use log::{Log, Metadata, Record};
+    use std::env;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+    fn entry() -> OtpEntry {
+        OtpEntry {
+            name: "GitHub".to_string(),
+            secret_hash: Secret::new(SECRET.to_string()),
+            ..OtpEntry::default()
+        }
+    }
+
+    #[test]
+    fn secret_formatting_is_redacted() {
+        let secret = Secret::new(SECRET.to_string());
+        assert!(!format!("{:?}", secret).contains(SECRET));
+        assert!(!format!("{}", secret).contains(SECRET));
+        assert_eq!(secret.expose(), SECRET);
+    }
+
+    #[test]
+    fn secret_gets_a_buffer_of_its_own_size() {
+        let mut value = String::with_capacity(64);
+        value.push_str(SECRET);
+        let secret = Secret::new(value);
+        assert_eq!(secret.expose(), SECRET);
+        assert_eq!((secret.0).0.capacity(), SECRET.len());
+    }
+
+    #[test]
+    fn entry_debug_output_has_no_secret() {
+        assert!(!format!("{:?}", entry()).contains(SECRET));
+        assert!(!format!("{:#?}", entry()).contains(SECRET));
+    }
+
+    #[test]
+    fn app_state_debug_output_has_no_secret() {
+        let app_state = AppState {
+            otp_entries: vec![entry()],
+            ..AppState::default()
+        };
+        assert!(!format!("{:?}", app_state).contains(SECRET));
+    }
+
+    #[test]
+    fn save_entry_event_debug_output_has_no_secret() {
+        let event = UiEvent::SaveEntry(Box::new(entry()), EntryAction::Add);
+        assert!(!format!("{:?}", event).contains(SECRET));
+    }
+
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    lazy_static! {
+        static ref CAPTURE_LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+    }
+
+    #[test]
+    fn log_output_has_no_secret() {
+        log::set_logger(&*CAPTURE_LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let config_dir = env::temp_dir().join(format!("otptray-test-{}", uuid::Uuid::new_v4()));
+        let config_location = ConfigLocation::under_dir(&config_dir);
+        let app_state = AppState {
+            config_location: config_location.clone(),
+            ..AppState::default()
+        };
+        let app_state = app_state
+            .save_entry(entry(), EntryAction::Add)
+            .unwrap()
+            .save_to_config()
+            .unwrap();
+        let loaded = AppState::load_from_config(&config_location).unwrap();
+        // What the tray logs for every event it handles.
+        log::debug!(
+            "Got UI event: {:?}",
+            UiEvent::SaveEntry(Box::new(loaded.otp_entries[0].clone()), EntryAction::Add)
+        );
+        log::debug!("Saved state: {:?}", app_state);
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        let lines = CAPTURE_LOGGER.0.lock().unwrap();
+        assert!(!lines.is_empty());
+        for line in lines.iter() {
+            assert!(!line.contains(SECRET), "secret in log line: {}", line);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pages_stay_locked_while_a_secret_is_on_them() {
+        let buffer = [0u8; 16];
+        let page = pages(buffer.as_ptr(), buffer.len()).next().unwrap();
+        lock_memory(buffer.as_ptr(), buffer.len());
+        lock_memory(buffer.as_ptr(), buffer.len());
+        let locked = LOCKED_PAGES.lock().unwrap().contains_key(&page);
+        unlock_memory(buffer.as_ptr(), buffer.len());
+        assert_eq!(LOCKED_PAGES.lock().unwrap().contains_key(&page), locked);
+        unlock_memory(buffer.as_ptr(), buffer.len());
+    }
+}
@@ -0,0 +1,312 @@
+//! Full-screen terminal dashboard with live codes, for headless boxes
+//! and terminal multiplexers where there's no tray to click.
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::common::*;
+
+/// How often the codes and countdowns are redrawn without input.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const COUNTDOWN_WIDTH: u64 = 10;
+/// Lines taken by the title, the search line and the status line.
+const CHROME_HEIGHT: u16 = 3;
+
+pub fn run(app_state: AppState) -> Result<(), Error> {
+    let mut stdout = io::stdout();
+    let _terminal = RawTerminal::enter(&mut stdout)?;
+    let mut dashboard = Dashboard {
+        app_state,
+        query: String::new(),
+        searching: false,
+        selected: 0,
+        folded: HashSet::new(),
+        status: None,
+    };
+    loop {
+        dashboard.draw(&mut stdout)?;
+        if !event::poll(REDRAW_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Release && !dashboard.handle_key(key, &mut stdout)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Raw mode on the alternate screen, restored when dropped, even if
+/// the dashboard bails out with an error.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter(stdout: &mut io::Stdout) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+enum Row<'a> {
+    Group {
+        name: String,
+        entry_count: usize,
+        folded: bool,
+    },
+    Entry(&'a OtpEntry),
+}
+
+struct Dashboard {
+    app_state: AppState,
+    query: String,
+    searching: bool,
+    selected: usize,
+    folded: HashSet<String>,
+    status: Option<String>,
+}
+
+impl Dashboard {
+    /// Ungrouped entries first, then each group under a header.
+    /// Groups are always unfolded while searching.
+    fn rows(&self) -> Vec<Row<'_>> {
+        let searching = !self.query.is_empty();
+        let entries = self
+            .app_state
+            .otp_entries
+            .iter()
+            .filter(|entry| entry.matches_search(&self.query))
+            .collect::<Vec<_>>();
+        let mut rows = entries
+            .iter()
+            .filter(|entry| entry.group.is_none())
+            .map(|entry| Row::Entry(entry))
+            .collect::<Vec<_>>();
+        for group in self.app_state.all_groups() {
+            let group_entries = entries
+                .iter()
+                .filter(|entry| entry.group.as_deref() == Some(group.as_str()))
+                .collect::<Vec<_>>();
+            if searching && group_entries.is_empty() {
+                continue;
+            }
+            let folded = !searching && self.folded.contains(&group);
+            rows.push(Row::Group {
+                name: group,
+                entry_count: group_entries.len(),
+                folded,
+            });
+            if !folded {
+                rows.extend(group_entries.into_iter().map(|entry| Row::Entry(entry)));
+            }
+        }
+        rows
+    }
+
+    /// Returns whether to keep running.
+    fn handle_key(&mut self, key: KeyEvent, stdout: &mut io::Stdout) -> io::Result<bool> {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Ok(false);
+        }
+        match (self.searching, key.code) {
+            (_, KeyCode::Up) | (false, KeyCode::Char('k')) => {
+                self.selected = self.selected.saturating_sub(1)
+            }
+            (_, KeyCode::Down) | (false, KeyCode::Char('j')) => self.selected += 1,
+            (_, KeyCode::Enter) => self.activate_selected(stdout)?,
+            (true, KeyCode::Esc) => {
+                self.searching = false;
+                self.query.clear();
+            }
+            (true, KeyCode::Backspace) => {
+                self.query.pop();
+                self.selected = 0;
+            }
+            (true, KeyCode::Char(c)) => {
+                self.query.push(c);
+                self.selected = 0;
+            }
+            (false, KeyCode::Char('q')) | (false, KeyCode::Esc) => return Ok(false),
+            (false, KeyCode::Char('/')) => self.searching = true,
+            (false, KeyCode::Char(' ')) => self.toggle_selected_group(None),
+            (false, KeyCode::Left) | (false, KeyCode::Char('h')) => {
+                self.toggle_selected_group(Some(true))
+            }
+            (false, KeyCode::Right) | (false, KeyCode::Char('l')) => {
+                self.toggle_selected_group(Some(false))
+            }
+            _ => {}
+        }
+        self.selected = self.selected.min(self.rows().len().saturating_sub(1));
+        Ok(true)
+    }
+
+    /// Copy the selected entry's code, or fold or unfold the selected
+    /// group.
+    fn activate_selected(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+        let entry = match self.rows().get(self.selected) {
+            Some(Row::Entry(entry)) => (*entry).clone(),
+            Some(Row::Group { .. }) => {
+                self.toggle_selected_group(None);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        copy_to_clipboard(stdout, &entry.get_otp_value().otp)?;
+        self.status = match self.app_state.record_use(entry.id) {
+            Ok(app_state) => {
+                self.app_state = app_state;
+                Some(format!("Copied the code for {}", entry.name))
+            }
+            Err(err) => Some(format!(
+                "Copied the code for {}, but couldn't save usage: {}",
+                entry.name, err
+            )),
+        };
+        Ok(())
+    }
+
+    /// Fold or unfold the group the selection is in. `None` toggles.
+    fn toggle_selected_group(&mut self, fold: Option<bool>) {
+        let group = match self.rows().get(self.selected) {
+            Some(Row::Group { name, .. }) => name.clone(),
+            Some(Row::Entry(entry)) => match &entry.group {
+                Some(group) => group.clone(),
+                None => return,
+            },
+            None => return,
+        };
+        let fold = fold.unwrap_or_else(|| !self.folded.contains(&group));
+        if fold {
+            // Keep the selection on the header of the folded group.
+            self.selected = self
+                .rows()
+                .iter()
+                .position(|row| matches!(row, Row::Group { name, .. } if *name == group))
+                .unwrap_or(self.selected);
+            self.folded.insert(group);
+        } else {
+            self.folded.remove(&group);
+        }
+    }
+
+    fn draw(&self, stdout: &mut io::Stdout) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let list_height = height.saturating_sub(CHROME_HEIGHT).max(1) as usize;
+        let rows = self.rows();
+        let first_row = self.selected.saturating_sub(list_height - 1);
+        let name_width = rows
+            .iter()
+            .filter_map(|row| match row {
+                Row::Entry(entry) => Some(entry.name.chars().count() + 2),
+                Row::Group { .. } => None,
+            })
+            .max()
+            .unwrap_or(0)
+            .min(40);
+
+        queue!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+        queue!(
+            stdout,
+            SetAttribute(Attribute::Bold),
+            Print(truncate(
+                "otptray · ↑↓ move · Enter copy · / search · ←→ fold · q quit",
+                width
+            )),
+            SetAttribute(Attribute::Reset),
+            MoveTo(0, 1),
+        )?;
+        let search_line = if self.searching || !self.query.is_empty() {
+            format!(
+                "Search: {}{}",
+                self.query,
+                if self.searching { "_" } else { "" }
+            )
+        } else {
+            String::new()
+        };
+        queue!(stdout, Print(truncate(&search_line, width)))?;
+
+        for (line, (index, row)) in rows
+            .iter()
+            .enumerate()
+            .skip(first_row)
+            .take(list_height)
+            .enumerate()
+        {
+            let text = match row {
+                Row::Group {
+                    name,
+                    entry_count,
+                    folded,
+                } => format!(
+                    "{} {} ({})",
+                    if *folded { "▸" } else { "▾" },
+                    name,
+                    entry_count
+                ),
+                Row::Entry(entry) => {
+                    let indent = if entry.group.is_some() { "  " } else { "" };
+                    let remaining = entry.seconds_remaining();
+                    let filled = (remaining * COUNTDOWN_WIDTH / entry.step.max(1))
+                        .min(COUNTDOWN_WIDTH) as usize;
+                    format!(
+                        "{}{:name_width$} {}  {}{} {:>2}s",
+                        indent,
+                        entry.name,
+                        entry.get_otp_value().otp,
+                        "█".repeat(filled),
+                        "░".repeat(COUNTDOWN_WIDTH as usize - filled),
+                        remaining,
+                        name_width = name_width.saturating_sub(indent.len())
+                    )
+                }
+            };
+            queue!(stdout, MoveTo(0, line as u16 + 2))?;
+            if index == self.selected {
+                queue!(stdout, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(
+                stdout,
+                Print(truncate(&text, width)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+        if rows.is_empty() {
+            queue!(stdout, MoveTo(0, 2), Print("No matching entries"))?;
+        }
+
+        if let Some(ref status) = self.status {
+            queue!(
+                stdout,
+                MoveTo(0, height.saturating_sub(1)),
+                Print(truncate(status, width))
+            )?;
+        }
+        stdout.flush()
+    }
+}
+
+fn truncate(text: &str, width: u16) -> String {
+    text.chars().take(width as usize).collect()
+}
+
+/// Set the clipboard through the terminal with an OSC 52 escape, so
+/// it also works over SSH and in tmux with `set-clipboard on`.
+fn copy_to_clipboard(stdout: &mut io::Stdout, text: &str) -> io::Result<()> {
+    write!(stdout, "\x1b]52;c;{}\x07", base64::encode(text))?;
+    stdout.flush()
+}